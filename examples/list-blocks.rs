@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use blk_reader::BlockReader;
 use blk_reader::BlockReaderOptions;
+use blk_reader::Checkpoint;
 
 use clap::Parser;
 
@@ -27,6 +28,14 @@ struct Args {
     #[arg(long, default_value_t = 10_000)]
     max_orphans: usize,
 
+    /// Memory the orphans may hold before the oldest ones are evicted, in bytes
+    #[arg(long)]
+    max_orphans_mem: Option<usize>,
+
+    /// Write the evicted orphans to a temporary file in this directory
+    #[arg(long)]
+    spill_dir: Option<std::path::PathBuf>,
+
     /// Maximum number of blocks to read
     #[arg(long, default_value_t = 850_150)]
     max_blocks: u32,
@@ -34,10 +43,39 @@ struct Args {
     /// Maximum number of block files to read
     #[arg(long = "max-files", default_value_t = 0)]
     max_blk_files: usize,
+
+    /// Network of the block files (bitcoin, testnet, testnet4, signet, regtest)
+    #[arg(long, default_value_t = bitcoin::Network::Bitcoin)]
+    network: bitcoin::Network,
+
+    /// Height of the first block to list
+    #[arg(long, default_value_t = 0)]
+    start_height: u32,
+
+    /// Keep listing blocks as they are written by the node
+    #[arg(long)]
+    follow: bool,
+
+    /// Checkpoint file, read is resumed from it if it exists
+    #[arg(long)]
+    checkpoint: Option<std::path::PathBuf>,
+
+    /// Skip corrupt records instead of stopping
+    #[arg(long)]
+    recover: bool,
+
+    /// Write the fork tree to this file on SIGUSR1 or when the orphans limit is reached (.dot or .json)
+    #[arg(long)]
+    fork_tree: Option<std::path::PathBuf>,
+
+    /// Print the progress of the read to stderr every few seconds
+    #[arg(long)]
+    progress: bool,
 }
 
 // Usage: cargo run --example list-blocks -- --max-blocks 1000 --max-files 10 /path/to/blocks
-fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Args::parse();
 
     println!(
@@ -63,13 +101,27 @@ fn main() -> Result<(), std::io::Error> {
         } else {
             Some(args.max_orphans)
         },
+        max_orphans_mem: args.max_orphans_mem,
+        spill_dir: args.spill_dir.clone(),
+        network: args.network,
+        start_height: Some(args.start_height),
+        follow: args.follow,
+        checkpoint_path: args.checkpoint.clone(),
+        checkpoint_interval: Some(10_000),
+        recover: args.recover,
+        fork_tree_path: args.fork_tree.clone(),
+        progress_interval: std::time::Duration::from_secs(5),
         ..Default::default()
     };
 
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&options.stop_flag))?;
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&options.stop_flag))?;
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&options.dump_flag))?;
 
-    let mut reader = BlockReader::new(options);
+    let mut reader = match args.checkpoint {
+        Some(ref path) if path.exists() => BlockReader::resume(options, Checkpoint::load(path)?),
+        _ => BlockReader::new(options),
+    };
 
     reader.set_block_cb(Box::new(|block, height| {
         let offset = &block.offset;
@@ -89,6 +141,21 @@ fn main() -> Result<(), std::io::Error> {
         );
     }));
 
+    if args.progress {
+        reader.set_progress_cb(Box::new(|progress| {
+            eprintln!(
+                "progress {:.2}% height={} {:.0} blocks/s {:.1} MB/s orphans={} mem={}MB eta={}s",
+                progress.ratio() * 100.0,
+                progress.height,
+                progress.blocks_per_sec,
+                progress.mb_per_sec(),
+                progress.orphans,
+                progress.mem / 1_000_000,
+                progress.eta.map_or(0, |eta| eta.as_secs())
+            );
+        }));
+    }
+
     reader.set_file_cb(Box::new(|file, height, time| {
         println!(
             "done reading {} {} {}",
@@ -98,6 +165,13 @@ fn main() -> Result<(), std::io::Error> {
         );
     }));
 
+    reader.set_skip_cb(Box::new(|range| {
+        println!(
+            "skipped {} bytes in {} (offset={}): {:?}",
+            range.len, range.file, range.offset, range.reason
+        );
+    }));
+
     reader.read(&args.path)?;
 
     Ok(())
@@ -1,10 +1,8 @@
-use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::sync::Arc;
 
-use bitcoin::block::Header;
 use bitcoin::ScriptBuf;
 use bitcoin::Amount;
 use bitcoin::TxOut;
@@ -78,21 +76,22 @@ impl From<&ScriptBuf> for ScriptType {
 }
 
 // https://github.com/bitcoin/bitcoin/blob/master/src/addresstype.cpp#L49
-impl ToString for ScriptType {
-  fn to_string(&self) -> String {
-      match self {
-          ScriptType::P2PK => "P2PK".to_string(),
-          ScriptType::P2PKH => "P2PKH".to_string(),
-          ScriptType::P2SH => "P2SH".to_string(),
-          ScriptType::P2WPKH => "P2WPKH".to_string(),
-          ScriptType::P2WSH => "P2WSH".to_string(),
-          ScriptType::P2TR => "P2TR".to_string(),
-          ScriptType::Empty => "Empty".to_string(),
-          ScriptType::OpReturn => "OpReturn".to_string(),
-          ScriptType::Multisig => "MultiSig".to_string(),
-          ScriptType::WitnessProgram => "WitnessProgram".to_string(),
-          ScriptType::Unknown => "UNKNOWN".to_string(),
-      }
+impl std::fmt::Display for ScriptType {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      let name = match self {
+          ScriptType::P2PK => "P2PK",
+          ScriptType::P2PKH => "P2PKH",
+          ScriptType::P2SH => "P2SH",
+          ScriptType::P2WPKH => "P2WPKH",
+          ScriptType::P2WSH => "P2WSH",
+          ScriptType::P2TR => "P2TR",
+          ScriptType::Empty => "Empty",
+          ScriptType::OpReturn => "OpReturn",
+          ScriptType::Multisig => "MultiSig",
+          ScriptType::WitnessProgram => "WitnessProgram",
+          ScriptType::Unknown => "UNKNOWN",
+      };
+      write!(f, "{}", name)
   }
 }
 
@@ -134,7 +133,7 @@ fn prepare_file(filename: &str) -> File {
         .unwrap();
 
     // Headers
-    file.write_all(format!("sep=;\n\"Block Time\"; Block; Tx:Vout; Value; Script\n").as_bytes())
+    file.write_all("sep=;\n\"Block Time\"; Block; Tx:Vout; Value; Script\n".as_bytes())
         .unwrap();
 
     file
@@ -161,7 +160,7 @@ fn write_data(
                 txid,
                 vout,
                 data.output.value.to_btc(),
-                data.output.script_pubkey.to_string()
+                data.output.script_pubkey
             )
             .as_bytes(),
         )
@@ -170,7 +169,8 @@ fn write_data(
 }
 
 // Usage: cargo run --example list-non-standard-txs -- --max-blocks 1000 --max-files 10 /path/to/blocks
-fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Args::parse();
 
     println!("Reading blocks: {:?}", args);
@@ -185,84 +185,70 @@ fn main() -> Result<(), std::io::Error> {
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&options.stop_flag))?;
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&options.stop_flag))?;
 
-    let unspent: BTreeMap<(Txid, u32), UnknownScriptData> = BTreeMap::new();
-    let spent: BTreeMap<(Txid, u32), UnknownScriptData> = BTreeMap::new();
-
-    let unspent = std::cell::RefCell::new(unspent);
-    let spent = std::cell::RefCell::new(spent);
+    let mut unspent: BTreeMap<(Txid, u32), UnknownScriptData> = BTreeMap::new();
+    let mut spent: BTreeMap<(Txid, u32), UnknownScriptData> = BTreeMap::new();
 
-    let last_block_height: RefCell<u32> = RefCell::new(0);
-    let last_block_header: RefCell<Option<Header>> = RefCell::new(None);
+    let mut last_block_height = 0;
+    let mut last_block_header = None;
 
     let mut reader = BlockReader::new(options);
 
-    reader.set_block_cb(
-        Box::new(|block, height| {
-            last_block_header.replace(Some(block.header));
-            last_block_height.replace(height);
+    for block in reader.blocks(&args.path) {
+        let (height, block) = block?;
 
-            let block = block.decode().unwrap();
+        last_block_header = Some(block.header);
+        last_block_height = height;
 
-            let mut unspent = unspent.borrow_mut();
+        let block = block.decode().unwrap();
 
-            for tx in block.txdata.iter() {
-                let mut txid: Option<Txid> = None; // Compute txid only if needed
+        for tx in block.txdata.iter() {
+            let mut txid: Option<Txid> = None; // Compute txid only if needed
 
-                for input in tx.input.iter() {
-                    let key = (input.previous_output.txid, input.previous_output.vout);
+            for input in tx.input.iter() {
+                let key = (input.previous_output.txid, input.previous_output.vout);
 
-                    // Skip coinbase
-                    if input.previous_output.is_null() {
-                        continue;
-                    }
-
-                    // Remove from unspent and add to spent
-                    match unspent.remove(&key) {
-                        Some(value) => {
-                            spent.borrow_mut().insert(key, value);
-                        }
-                        None => {}
-                    }
+                // Skip coinbase
+                if input.previous_output.is_null() {
+                    continue;
                 }
 
-                for (vout, output) in tx.output.iter().enumerate() {
-                    let script_type = ScriptType::from(&output.script_pubkey);
-
-                    if script_type == ScriptType::Unknown {
-                        let txid = match txid {
-                            Some(txid) => txid,
-                            None => {
-                                let computed = tx.compute_txid();
-                                txid = Some(computed.clone());
-                                computed
-                            },
-                        };
-
-                        let key = (txid, vout as u32);
-
-                        unspent.insert(
-                            key,
-                            UnknownScriptData {
-                                time: block.header.time,
-                                height,
-                                output: output.clone(),
-                            },
-                        );
-                    }
+                // Remove from unspent and add to spent
+                if let Some(value) = unspent.remove(&key) {
+                    spent.insert(key, value);
                 }
             }
-        })
-    );
 
-    reader.read(&args.path)?;
+            for (vout, output) in tx.output.iter().enumerate() {
+                let script_type = ScriptType::from(&output.script_pubkey);
+
+                if script_type == ScriptType::Unknown {
+                    let txid = match txid {
+                        Some(txid) => txid,
+                        None => {
+                            let computed = tx.compute_txid();
+                            txid = Some(computed);
+                            computed
+                        },
+                    };
+
+                    let key = (txid, vout as u32);
+
+                    unspent.insert(
+                        key,
+                        UnknownScriptData {
+                            time: block.header.time,
+                            height,
+                            output: output.clone(),
+                        },
+                    );
+                }
+            }
+        }
+    }
 
-    let last_block_height = last_block_height.take();
-    let last_block_id = last_block_header.take().unwrap();
+    let last_block_id = last_block_header.unwrap();
     println!("Done reading blocks. Last block is {} {}", last_block_height, last_block_id.block_hash());
 
-    let spent = spent.borrow();
-    let unspent = unspent.borrow();
-
     let unspent_filename = "non-standard-unspent.csv";
     let mut unspent_file = prepare_file(unspent_filename);
     println!("Writing {} items into {}", unspent.len(), unspent_filename);
@@ -1,4 +1,3 @@
-use std::cell::RefCell;
 use std::sync::Arc;
 
 use blk_reader::BlockReader;
@@ -29,7 +28,8 @@ struct Args {
 }
 
 // Usage: cargo run --example no-op -- --max-blocks 1000 --max-files 10 /path/to/blocks
-fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Args::parse();
 
     println!(
@@ -49,24 +49,20 @@ fn main() -> Result<(), std::io::Error> {
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&options.stop_flag))?;
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&options.stop_flag))?;
 
-    let last_block_height = RefCell::new(0);
-    let last_block: RefCell<Option<LazyBlock>> = RefCell::new(None);
+    let mut last_block_height = 0;
+    let mut last_block: Option<LazyBlock> = None;
 
     let mut reader = BlockReader::new(options);
 
-    reader.set_block_cb(
-        Box::new(|block, height| {
-            // Do nothing to evaluate time to read (and order) blocks
-            // without any processing
-            last_block_height.replace(height);
-            last_block.replace(Some(block));
-        })
-    );
-
-    reader.read(&args.path)?;
+    for block in reader.blocks(&args.path) {
+        // Do nothing to evaluate time to read (and order) blocks
+        // without any processing
+        let (height, block) = block?;
+        last_block_height = height;
+        last_block = Some(block);
+    }
 
-    let last_block_height = last_block_height.take();
-    let last_block = last_block.take().unwrap();
+    let last_block = last_block.unwrap();
     let header = last_block.header;
 
     println!("Read {} blocks", 1 + last_block_height);
@@ -1,40 +1,148 @@
+use std::borrow::Cow;
 use std::fs;
 use std::fs::File;
 use std::io::BufReader;
-use std::io::Error;
-use std::io::ErrorKind;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::fmt::Display;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 use std::vec;
 
 use bitcoin::block::Header;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::consensus::encode::serialize;
 use bitcoin::consensus::Decodable;
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::sha256d;
 use bitcoin::hashes::Hash;
 use bitcoin::p2p::Magic;
 use bitcoin::Block;
 use bitcoin::BlockHash;
+use bitcoin::Network;
+use bitcoin::Script;
 use bitcoin::Transaction;
-
-static MAGIC: Magic = Magic::BITCOIN;
+use bitcoin::Weight;
+use log::debug;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::chain::Chain;
+use crate::chain::Duplicate;
+use crate::chain::ForkChoice;
 use crate::chain::GetBlockIds;
+use crate::chain::OrphanEviction;
+use crate::chain::StaleBranch;
+use crate::checkpoint::BlockLocation;
+use crate::checkpoint::Checkpoint;
+use crate::error::ReadError;
+use crate::fork_tree::ForkTree;
+use crate::progress::Progress;
+use crate::progress::ProgressMeter;
+use crate::progress::ProgressState;
+use crate::spill::OrphanSpill;
+use crate::xor::read_xor_key;
+use crate::xor::xor;
+use crate::xor::XorKey;
+use crate::xor::XorReader;
 
 #[derive(Debug, Clone)]
 pub struct LazyBlock {
     pub blk_index: u32,
     pub blk_path: String,
     pub offset: u64,
+    /// Size of the serialized block
+    pub size: u32,
     pub header: Header,
-    data: Vec<u8>,
+    body: Body,
+}
+
+/// Serialized transactions of a block
+#[derive(Debug, Clone)]
+enum Body {
+    /// Held in memory
+    Loaded(Vec<u8>),
+    /// Left in the blk file, read with the obfuscation key when needed
+    OnDisk(XorKey),
+    /// Not read (headers only mode)
+    Skipped,
+}
+
+impl Body {
+    /// Return the body of a block from the transactions data read with its header
+    fn new(data: Option<Vec<u8>>, headers_only: bool, xor_key: XorKey) -> Body {
+        match data {
+            Some(data) => Body::Loaded(data),
+            None if headers_only => Body::Skipped,
+            None => Body::OnDisk(xor_key),
+        }
+    }
 }
 
 impl LazyBlock {
-    pub fn decode(&self) -> Result<Block, bitcoin::consensus::encode::Error> {
-        let mut txdata: &[u8] = &self.data[..];
-        let txdata = Vec::<Transaction>::consensus_decode(&mut txdata)?;
+    /// Return the serialized transactions, read from the blk file if they are not in memory
+    fn txdata(&self) -> Result<Cow<'_, [u8]>, ReadError> {
+        let xor_key = match self.body {
+            Body::Loaded(ref data) => return Ok(Cow::Borrowed(data)),
+            Body::OnDisk(xor_key) => xor_key,
+            Body::Skipped => {
+                return Err(ReadError::NoBody {
+                    file: self.blk_path.clone(),
+                    offset: self.offset,
+                })
+            }
+        };
+
+        // Skip the magic, the size and the header of the record
+        let pos = self.offset + 8 + Header::SIZE as u64;
+        let mut file =
+            File::open(&self.blk_path).map_err(|err| ReadError::io(&self.blk_path, err))?;
+        file.seek(SeekFrom::Start(pos))
+            .map_err(|err| ReadError::io(&self.blk_path, err))?;
+
+        let mut data = vec![0; self.size as usize - Header::SIZE];
+        XorReader::new(file, xor_key, pos)
+            .read_exact(&mut data)
+            .map_err(|err| ReadError::io(&self.blk_path, err))?;
+
+        Ok(Cow::Owned(data))
+    }
+
+    /// Read the transactions from the blk file if they are not in memory
+    fn load_body(&mut self) -> Result<(), ReadError> {
+        if let Body::OnDisk(_) = self.body {
+            self.body = Body::Loaded(self.txdata()?.into_owned());
+        }
+        Ok(())
+    }
+
+    /// Return the serialized block, as stored in the blk file
+    pub fn raw(&self) -> Result<Vec<u8>, ReadError> {
+        let mut raw = serialize(&self.header);
+        raw.extend_from_slice(&self.txdata()?);
+        Ok(raw)
+    }
+
+    pub fn decode(&self) -> Result<Block, ReadError> {
+        let data = self.txdata()?;
+        let mut txdata: &[u8] = &data;
+        let txdata = Vec::<Transaction>::consensus_decode(&mut txdata).map_err(|source| {
+            ReadError::Decode {
+                file: self.blk_path.clone(),
+                offset: self.offset,
+                source,
+            }
+        })?;
         Ok(Block {
             header: self.header,
             txdata,
@@ -50,21 +158,543 @@ impl GetBlockIds<BlockHash> for LazyBlock {
     fn get_block_prev_id(&self) -> BlockHash {
         self.header.prev_blockhash
     }
+
+    fn get_block_work(&self) -> u128 {
+        // The work of a single block always fits in the lower 128 bits
+        let work = self.header.work().to_le_bytes();
+        u128::from_le_bytes(work[..16].try_into().unwrap())
+    }
+
+    fn get_block_time(&self) -> u32 {
+        self.header.time
+    }
+
+    fn get_block_mem_size(&self) -> usize {
+        let data = match self.body {
+            Body::Loaded(ref data) => data.capacity(),
+            _ => 0,
+        };
+        std::mem::size_of::<LazyBlock>() + self.blk_path.capacity() + data
+    }
+}
+
+/// Orphan block as written to the spill file
+#[derive(Serialize, Deserialize)]
+struct SpilledBlock {
+    blk_index: u32,
+    blk_path: String,
+    offset: u64,
+    size: u32,
+    // Serialized header
+    header: Vec<u8>,
+    data: Option<Vec<u8>>,
+}
+
+impl SpilledBlock {
+    fn new(block: LazyBlock) -> SpilledBlock {
+        SpilledBlock {
+            blk_index: block.blk_index,
+            blk_path: block.blk_path,
+            offset: block.offset,
+            size: block.size,
+            header: serialize(&block.header),
+            data: match block.body {
+                Body::Loaded(data) => Some(data),
+                _ => None,
+            },
+        }
+    }
+
+    fn into_block(self, headers_only: bool, xor_key: XorKey) -> Result<LazyBlock, ReadError> {
+        let header = deserialize(&self.header).map_err(|source| ReadError::Decode {
+            file: self.blk_path.clone(),
+            offset: self.offset,
+            source,
+        })?;
+
+        Ok(LazyBlock {
+            blk_index: self.blk_index,
+            blk_path: self.blk_path,
+            offset: self.offset,
+            size: self.size,
+            header,
+            body: Body::new(self.data, headers_only, xor_key),
+        })
+    }
+}
+
+/// Change of the best chain, reported as soon as a block is read
+#[derive(Debug, Clone)]
+pub enum BlockEvent {
+    /// The block is now part of the best chain at the given height
+    BlockConnected(u32, LazyBlock),
+    /// The block left the best chain after a reorganization
+    BlockDisconnected(u32, LazyBlock),
+}
+
+/// Block removed from the index because it lost against the best chain
+#[derive(Debug, Clone)]
+pub struct StaleBlock {
+    pub block: LazyBlock,
+    /// Height the block would have in the best chain
+    pub height: u32,
+    /// Last block shared with the best chain
+    pub fork_point: BlockHash,
+    pub fork_height: u32,
+    /// Number of blocks of the longest path of the stale branch
+    pub branch_len: u32,
+}
+
+/// Summary of a fork found while reading the blk files
+#[derive(Debug, Clone)]
+pub struct ForkReport {
+    /// Last block shared with the best chain
+    pub fork_point: BlockHash,
+    pub fork_height: u32,
+    /// Number of blocks of the longest path of the stale branch
+    pub branch_len: u32,
+    /// Hashes of the stale blocks
+    pub blocks: Vec<BlockHash>,
+}
+
+/// Block stored more than once in the blk files, only the first copy is kept
+#[derive(Debug, Clone)]
+pub struct DuplicateBlock {
+    pub hash: BlockHash,
+    /// Position of the copy kept, None if it was already delivered
+    pub first: Option<BlockLocation>,
+    /// Position of the copy ignored
+    pub duplicate: BlockLocation,
+}
+
+/// Bytes found on disk in the space preallocated by Bitcoin Core at the end of blk files
+/// The preallocated space is not obfuscated
+const PREALLOCATED: [u8; 4] = [0; 4];
+
+/// Capacity of the buffer used to read the blk files with the block bodies
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Reason why a range of a blk file was not read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Zeros preallocated by Bitcoin Core after the last block of the file
+    Preallocated,
+    /// Bytes that don't hold a valid record, skipped in recovery mode
+    Corrupt,
+}
+
+/// Range of a blk file skipped by the reader
+#[derive(Debug, Clone)]
+pub struct SkippedRange {
+    pub file: String,
+    pub offset: u64,
+    pub len: u64,
+    pub reason: SkipReason,
+}
+
+type BlockCallback<'call> = Box<dyn Fn(LazyBlock, u32) + Send + 'call>;
+type FileCallback<'call> = Box<dyn Fn(String, u32, u32) + Send + 'call>;
+type EventCallback<'call> = Box<dyn Fn(BlockEvent) + Send + 'call>;
+type StaleCallback<'call> = Box<dyn Fn(StaleBlock) + Send + 'call>;
+type SkipCallback<'call> = Box<dyn Fn(SkippedRange) + Send + 'call>;
+type DuplicateCallback<'call> = Box<dyn Fn(DuplicateBlock) + Send + 'call>;
+type ProgressCallback<'call> = Box<dyn Fn(Progress) + Send + 'call>;
+
+/// Size, header and transactions data of a block record
+type Record = (u32, Header, Option<Vec<u8>>);
+
+/// The blk file being read
+struct BlkFile {
+    reader: BufReader<XorReader<File>>,
+    xor_key: XorKey,
+    path: String,
+    index: u32,
+    offset: u64,
+    size: u64,
+    // Height and time of the last block read, passed to the file callback
+    last_height: u32,
+    last_time: u32,
+}
+
+impl BlkFile {
+    /// Open a blk file at the given offset
+    /// Without the bodies, the buffer only holds the magic, the size and the header of a record
+    fn open(
+        path: String,
+        xor_key: XorKey,
+        offset: u64,
+        with_body: bool,
+    ) -> Result<BlkFile, ReadError> {
+        let index = blk_index(&path)?;
+
+        let mut file = File::open(&path).map_err(|err| ReadError::io(&path, err))?;
+        let size = file
+            .metadata()
+            .map_err(|err| ReadError::io(&path, err))?
+            .len();
+
+        // The position comes from a checkpoint made on other files
+        if offset > size {
+            return Err(ReadError::BadOffset {
+                file: path,
+                offset,
+                size,
+            });
+        }
+
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|err| ReadError::io(&path, err))?;
+        }
+
+        let capacity = if with_body {
+            DEFAULT_BUF_SIZE
+        } else {
+            8 + Header::SIZE
+        };
+
+        Ok(BlkFile {
+            reader: BufReader::with_capacity(capacity, XorReader::new(file, xor_key, offset)),
+            xor_key,
+            path,
+            index,
+            offset,
+            size,
+            last_height: 0,
+            last_time: 0,
+        })
+    }
+
+    /// Read the record at the current offset, return its size, header and transactions data
+    /// The transactions are skipped unless `with_body` is set
+    /// Return None if the file has no more records, the rest of the file is preallocated
+    fn read_record(&mut self, magic: Magic, with_body: bool) -> Result<Option<Record>, ReadError> {
+        let available = self.size.saturating_sub(self.offset);
+
+        // Magic and size
+        if available < 8 {
+            return Err(self.truncated(8, available));
+        }
+
+        let found = Magic::consensus_decode(&mut self.reader).map_err(|err| self.decode(err))?;
+        if self.is_preallocated(found.to_bytes(), self.offset) {
+            return Ok(None);
+        }
+
+        if found != magic {
+            return Err(ReadError::BadMagic {
+                file: self.path.clone(),
+                offset: self.offset,
+                found,
+            });
+        }
+
+        let size = u32::consensus_decode(&mut self.reader).map_err(|err| self.decode(err))?;
+        if self.is_preallocated(size.to_le_bytes(), self.offset + 4) {
+            return Ok(None);
+        }
+
+        if (size as usize) < Header::SIZE || size as u64 > Weight::MAX_BLOCK.to_wu() {
+            return Err(ReadError::BadSize {
+                file: self.path.clone(),
+                offset: self.offset,
+                size,
+            });
+        }
+
+        if 8 + size as u64 > available {
+            return Err(self.truncated(8 + size as u64, available));
+        }
+
+        // Read the block header
+        let header = Header::consensus_decode(&mut self.reader).map_err(|err| self.decode(err))?;
+
+        let body_size = size as usize - Header::SIZE;
+        if !with_body {
+            // Move to the next record without reading the transactions
+            self.reader
+                .seek_relative(body_size as i64)
+                .map_err(|err| ReadError::io(&self.path, err))?;
+            return Ok(Some((size, header, None)));
+        }
+
+        // Read the rest of the block
+        let mut data = vec![0; body_size];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|err| ReadError::io(&self.path, err))?;
+
+        Ok(Some((size, header, Some(data))))
+    }
+
+    /// Return true if another record starts after the record just read
+    /// The node writes the records in order, so a record followed by another one is complete
+    fn is_followed(&mut self, size: u32) -> Result<bool, ReadError> {
+        let end = self.offset + 8 + size as u64;
+        if end + 4 > self.size {
+            return Ok(false);
+        }
+
+        let mut magic = [0; 4];
+        self.reader
+            .read_exact(&mut magic)
+            .and_then(|_| self.reader.seek_relative(-4))
+            .map_err(|err| ReadError::io(&self.path, err))?;
+
+        Ok(!self.is_preallocated(magic, end))
+    }
+
+    /// Return true if the transactions of the record just read match its header
+    /// A record being written to the preallocated space fits in the file, but the
+    /// end of its transactions is still zeros. The transactions are read from the
+    /// file if they were skipped
+    fn is_written(
+        &mut self,
+        size: u32,
+        header: &Header,
+        data: Option<&[u8]>,
+    ) -> Result<bool, ReadError> {
+        let data = match data {
+            Some(data) => Cow::Borrowed(data),
+            None => {
+                let mut data = vec![0; size as usize - Header::SIZE];
+                self.reader
+                    .seek_relative(-(data.len() as i64))
+                    .and_then(|_| self.reader.read_exact(&mut data))
+                    .map_err(|err| ReadError::io(&self.path, err))?;
+                Cow::Owned(data)
+            }
+        };
+
+        let txdata = match deserialize::<Vec<Transaction>>(&data) {
+            Ok(txdata) => txdata,
+            Err(_) => return Ok(false),
+        };
+
+        let block = Block {
+            header: *header,
+            txdata,
+        };
+        Ok(block.check_merkle_root())
+    }
+
+    /// Move to the given offset of the file
+    fn seek(&mut self, offset: u64) -> Result<(), ReadError> {
+        self.reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|err| ReadError::io(&self.path, err))?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Find the first valid record after the current offset and move to it
+    /// A record is valid if it starts with the magic, fits in the file and decodes
+    /// Move to the end of the file if there is no valid record left
+    fn resync(&mut self, magic: Magic) -> Result<(), ReadError> {
+        let mut from = self.offset + 1;
+
+        while let Some(candidate) = self.find_magic(magic, from)? {
+            self.seek(candidate)?;
+            if let Ok(Some((_, _, Some(data)))) = self.read_record(magic, true) {
+                if Vec::<Transaction>::consensus_decode(&mut &data[..]).is_ok() {
+                    return self.seek(candidate);
+                }
+            }
+
+            from = candidate + 1;
+        }
+
+        self.seek(self.size)
+    }
+
+    /// Return the offset of the next occurrence of the magic from the given offset
+    fn find_magic(&mut self, magic: Magic, from: u64) -> Result<Option<u64>, ReadError> {
+        self.seek(from)?;
+
+        let magic = magic.to_bytes();
+        let mut window = [0; 4];
+        let mut byte = [0];
+        for offset in from..self.size {
+            self.reader
+                .read_exact(&mut byte)
+                .map_err(|err| ReadError::io(&self.path, err))?;
+            window.rotate_left(1);
+            window[3] = byte[0];
+
+            if offset >= from + 3 && window == magic {
+                return Ok(Some(offset - 3));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Return true if the bytes read at this offset are zeros on disk
+    fn is_preallocated(&self, mut bytes: [u8; 4], offset: u64) -> bool {
+        xor(&mut bytes, &self.xor_key, offset);
+        bytes == PREALLOCATED
+    }
+
+    fn truncated(&self, expected: u64, available: u64) -> ReadError {
+        ReadError::Truncated {
+            file: self.path.clone(),
+            offset: self.offset,
+            expected,
+            available,
+        }
+    }
+
+    fn decode(&self, source: bitcoin::consensus::encode::Error) -> ReadError {
+        ReadError::Decode {
+            file: self.path.clone(),
+            offset: self.offset,
+            source,
+        }
+    }
+}
+
+/// Return true if a record may start at this offset of the file
+fn has_record_at(path: &str, offset: u64) -> bool {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return true,
+    };
+
+    let mut magic = [0; 4];
+    match file
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut magic))
+    {
+        Ok(()) => magic != PREALLOCATED,
+        Err(_) => false,
+    }
+}
+
+/// Create the index of the blocks not delivered yet, built on the given block
+fn new_chain(
+    options: &BlockReaderOptions,
+    genesis_identifier: BlockHash,
+) -> Chain<BlockHash, LazyBlock> {
+    let mut chain = Chain::new(genesis_identifier);
+    chain.set_fork_choice(options.fork_choice);
+    chain.set_max_orphans_mem(options.max_orphans_mem, options.orphan_eviction);
+    chain.set_track_changes(true);
+    chain
+}
+
+/// Extract the index of a blk file from its path (blkNNNNN.dat)
+fn blk_index(path: &str) -> Result<u32, ReadError> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("blk"))
+        .and_then(|name| name.strip_suffix(".dat"))
+        .and_then(|index| index.parse::<u32>().ok())
+        .ok_or_else(|| ReadError::BadFileName {
+            file: path.to_string(),
+        })
 }
 
 pub struct BlockReader<'call> {
     height: u32,
     chain: Chain<BlockHash, LazyBlock>,
-    block_cb: Option<Box<dyn Fn(LazyBlock, u32) + 'call>>,
-    file_cb: Option<Box<dyn Fn(String, u32, u32) + 'call>>,
+    block_cb: Option<BlockCallback<'call>>,
+    file_cb: Option<FileCallback<'call>>,
+    event_cb: Option<EventCallback<'call>>,
+    stale_cb: Option<StaleCallback<'call>>,
+    skip_cb: Option<SkipCallback<'call>>,
+    duplicate_cb: Option<DuplicateCallback<'call>>,
+    progress_cb: Option<ProgressCallback<'call>>,
     options: BlockReaderOptions,
+    xor_key: XorKey,
+    genesis_hash: BlockHash,
+    // Blocks that left the index, waiting to be delivered
+    ready: VecDeque<(u32, LazyBlock)>,
+    // Directory being read and files left to read
+    dir_path: PathBuf,
+    files: VecDeque<String>,
+    file: Option<BlkFile>,
+    done: bool,
+    unburied_height: Option<u32>,
+    // Blocks of the index reported as connected, from the head of the chain
+    connected: VecDeque<BlockHash>,
+    forks: Vec<ForkReport>,
+    // Hash of the last block that left the index
+    tip_hash: BlockHash,
+    // Hashes of the last blocks that left the index, to recognise their copies
+    recent: VecDeque<BlockHash>,
+    recent_ids: BTreeSet<BlockHash>,
+    // Position of the next record to read
+    position: Option<BlockLocation>,
+    // Blocks of a checkpoint to read again before reading new files
+    pending: Vec<BlockLocation>,
+    checkpoint_height: u32,
+    // Start hash not delivered yet, blocks before it are skipped
+    waiting_for: Option<BlockHash>,
+    // Orphans evicted from memory, created on the first eviction
+    spill: Option<OrphanSpill>,
+    dropped_orphans: u64,
+    duplicates: u64,
+    progress: ProgressMeter,
 }
 
 pub struct BlockReaderOptions {
     pub max_blocks: Option<u32>,
     pub max_orphans: Option<usize>,
+    /// Memory the orphans may hold before some of them are evicted,
+    /// in the order given by `orphan_eviction`
+    pub max_orphans_mem: Option<usize>,
+    pub orphan_eviction: OrphanEviction,
+    /// Write the evicted orphans to a temporary file in this directory and read
+    /// them back once their parent is found, instead of dropping them
+    pub spill_dir: Option<PathBuf>,
     pub max_blk_files: Option<usize>,
+    /// Obfuscation key of the blk files, read from xor.dat when not set
+    pub xor_key: Option<XorKey>,
+    /// Network of the blk files, used to check the magic and find the genesis block
+    pub network: Network,
+    /// Override the network magic, for custom signets (see `signet_magic`)
+    pub magic: Option<Magic>,
+    /// Don't deliver the blocks below this height
+    pub start_height: Option<u32>,
+    /// Don't deliver the blocks before this one, the block itself is delivered
+    /// The read fails with `ReadError::StartNotFound` if it is not in the best chain
+    pub start_hash: Option<BlockHash>,
+    /// Number of blocks of the best chain, the block itself included, needed before
+    /// a block is delivered: with the default of 10, 9 blocks are built on top of it
+    pub confirmation_depth: u32,
+    /// Rule used to select the best chain when blocks fork
+    pub fork_choice: ForkChoice,
+    /// Number of blocks that left the index whose hashes are kept to recognise
+    /// their copies stored later in the blk files
+    pub duplicate_window: usize,
+    /// Save a checkpoint to this path when the read stops and every `checkpoint_interval` blocks
+    pub checkpoint_path: Option<PathBuf>,
+    pub checkpoint_interval: Option<u32>,
+    /// Keep watching the directory for new blocks once all files are read
+    pub follow: bool,
+    /// Delay between two checks of the directory in follow mode
+    pub poll_interval: Duration,
+    /// Deliver the blocks left in the index once all files are read,
+    /// even if they are not buried yet (see `BlockReader::unburied_height`)
+    pub flush_tip: bool,
+    /// Skip corrupt or truncated records instead of failing, reading continues
+    /// at the next valid record (see `BlockReader::set_skip_cb`)
+    pub recover: bool,
+    /// Only read the block headers, the transactions are skipped and
+    /// `LazyBlock::decode` fails
+    pub headers_only: bool,
+    /// Don't keep the transactions in memory, `LazyBlock::decode` and
+    /// `LazyBlock::raw` read them again from the blk file
+    pub lazy_body: bool,
+    /// Minimum delay between two calls of the progress callback
+    pub progress_interval: Duration,
     pub stop_flag: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Write the fork tree to this path when `dump_flag` is set or `max_orphans`
+    /// is reached, as DOT if its extension is `dot` and JSON otherwise
+    pub fork_tree_path: Option<PathBuf>,
+    /// Set to dump the fork tree, cleared once it is written
+    pub dump_flag: Arc<AtomicBool>,
 }
 
 impl Default for BlockReaderOptions {
@@ -72,201 +702,2045 @@ impl Default for BlockReaderOptions {
         BlockReaderOptions {
             max_blocks: Some(1_000),
             max_orphans: Some(10_000),
+            max_orphans_mem: None,
+            orphan_eviction: OrphanEviction::Oldest,
+            spill_dir: None,
             max_blk_files: None,
+            xor_key: None,
+            network: Network::Bitcoin,
+            magic: None,
+            start_height: None,
+            start_hash: None,
+            confirmation_depth: 10,
+            fork_choice: ForkChoice::Longest,
+            duplicate_window: 10_000,
+            checkpoint_path: None,
+            checkpoint_interval: None,
+            follow: false,
+            poll_interval: Duration::from_secs(1),
+            flush_tip: false,
+            recover: false,
+            headers_only: false,
+            lazy_body: false,
+            progress_interval: Duration::from_secs(1),
             stop_flag: Arc::new(AtomicBool::new(false)),
+            fork_tree_path: None,
+            dump_flag: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
+/// Compute the network magic of a custom signet from its challenge script
+/// The magic is the first 4 bytes of the double SHA256 of the serialized challenge
+pub fn signet_magic(challenge: &Script) -> Magic {
+    let hash = sha256d::Hash::hash(&serialize(challenge));
+    let bytes = hash.to_byte_array();
+    Magic::from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
 impl<'a> BlockReader<'a> {
     pub fn new(options: BlockReaderOptions) -> BlockReader<'a> {
+        // The genesis block is inserted like any other block, its hash is
+        // only used to check the first block that leaves the index
+        let genesis = genesis_block(options.network);
+        let genesis_hash = genesis.block_hash();
+
+        let chain = new_chain(&options, genesis.header.prev_blockhash);
+        let waiting_for = options.start_hash;
+
         BlockReader {
             height: 0,
-            chain: Chain::new(BlockHash::all_zeros()),
+            chain,
             block_cb: None,
             file_cb: None,
+            event_cb: None,
+            stale_cb: None,
+            skip_cb: None,
+            duplicate_cb: None,
+            progress_cb: None,
             options,
+            xor_key: Default::default(),
+            genesis_hash,
+            ready: VecDeque::new(),
+            dir_path: PathBuf::new(),
+            files: VecDeque::new(),
+            file: None,
+            done: false,
+            unburied_height: None,
+            connected: VecDeque::new(),
+            forks: vec![],
+            tip_hash: BlockHash::all_zeros(),
+            recent: VecDeque::new(),
+            recent_ids: BTreeSet::new(),
+            position: None,
+            pending: vec![],
+            checkpoint_height: 0,
+            waiting_for,
+            spill: None,
+            dropped_orphans: 0,
+            duplicates: 0,
+            progress: ProgressMeter::new(),
+        }
+    }
+
+    /// Create a reader that continues from a checkpoint
+    /// Files before the checkpoint position are not read again, and the start
+    /// hash still pending in the checkpoint replaces the `start_hash` option
+    pub fn resume(options: BlockReaderOptions, checkpoint: Checkpoint) -> BlockReader<'a> {
+        let mut reader = BlockReader::new(options);
+
+        // Before the genesis block is delivered, the chain starts from the null hash as usual
+        if checkpoint.height > 0 {
+            let best_hash = BlockHash::from_byte_array(checkpoint.best_hash);
+            reader.chain = new_chain(&reader.options, best_hash);
+            reader.tip_hash = best_hash;
         }
+
+        reader.height = checkpoint.height;
+        reader.checkpoint_height = checkpoint.height;
+        reader.position = Some(checkpoint.position);
+        reader.pending = checkpoint.pending;
+        reader.waiting_for = checkpoint.start_hash.map(BlockHash::from_byte_array);
+
+        reader
     }
 
-    pub fn set_block_cb(&mut self, block_cb: Box<dyn Fn(LazyBlock, u32) + 'a>) {
+    pub fn set_block_cb(&mut self, block_cb: Box<dyn Fn(LazyBlock, u32) + Send + 'a>) {
         self.block_cb = Some(block_cb);
     }
 
-    pub fn set_file_cb(&mut self, file_cb: Box<dyn Fn(String, u32, u32) + 'a>) {
+    pub fn set_file_cb(&mut self, file_cb: Box<dyn Fn(String, u32, u32) + Send + 'a>) {
         self.file_cb = Some(file_cb);
     }
 
+    /// Set a callback notified of every change of the best chain
+    /// Blocks are connected before being buried and disconnected if the best chain changes
+    pub fn set_event_cb(&mut self, event_cb: Box<dyn Fn(BlockEvent) + Send + 'a>) {
+        self.event_cb = Some(event_cb);
+    }
+
+    /// Set a callback receiving every block removed from the index because it
+    /// is not part of the best chain
+    pub fn set_stale_cb(&mut self, stale_cb: Box<dyn Fn(StaleBlock) + Send + 'a>) {
+        self.stale_cb = Some(stale_cb);
+    }
+
+    /// Set a callback receiving the ranges of the blk files that don't contain blocks
+    pub fn set_skip_cb(&mut self, skip_cb: Box<dyn Fn(SkippedRange) + Send + 'a>) {
+        self.skip_cb = Some(skip_cb);
+    }
+
+    /// Set a callback receiving the blocks found more than once in the blk files
+    pub fn set_duplicate_cb(&mut self, duplicate_cb: Box<dyn Fn(DuplicateBlock) + Send + 'a>) {
+        self.duplicate_cb = Some(duplicate_cb);
+    }
+
+    /// Set a callback receiving the progress of the read every `progress_interval`
+    /// and once all blocks are delivered
+    pub fn set_progress_cb(&mut self, progress_cb: Box<dyn Fn(Progress) + Send + 'a>) {
+        self.progress_cb = Some(progress_cb);
+    }
+
     /// Read the directory and return a list of files
-    fn read_dir(&self, dir_path: &std::path::Path) -> Result<Vec<String>, Error> {
-        let mut entries: Vec<String> = fs::read_dir(dir_path)?
+    fn read_dir(&self, dir_path: &Path) -> Result<Vec<String>, ReadError> {
+        let mut entries: Vec<String> = fs::read_dir(dir_path)
+            .map_err(|err| ReadError::io(dir_path.display(), err))?
             .filter_map(Result::ok)
             .map(|d| d.path())
-            .filter(|d| d.is_file() && d.extension().is_some())
-            .map(|d| d.to_str().unwrap().to_string())
-            .filter(|s| s.contains("/blk") && s.ends_with(".dat"))
-            .collect();
+            .filter(|d| d.is_file())
+            .filter(|d| {
+                let name = d.file_name().unwrap_or_default().to_string_lossy();
+                name.starts_with("blk") && name.ends_with(".dat")
+            })
+            .map(|d| {
+                d.into_os_string()
+                    .into_string()
+                    .map_err(|path| ReadError::BadFileName {
+                        file: path.to_string_lossy().to_string(),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
 
         entries.sort();
 
-        match self.options.max_blk_files {
-            Some(max_blk_files) => entries.truncate(max_blk_files),
-            None => (),
+        if let Some(max_blk_files) = self.options.max_blk_files {
+            entries.truncate(max_blk_files);
         }
 
-        return Ok(entries);
+        Ok(entries)
     }
 
-    /// Read blocks from a file and insert them into the index
-    /// Return true if there are more blocks to read, false if we reached the end of the file
-    fn read_blocs(&mut self, file_path: &str) -> Result<bool, Error> {
-        let file = File::open(file_path)?;
-        let file_size = file.metadata().unwrap().len();
+    /// Open the next blk file to read
+    /// Return false if there are no more files to read
+    fn open_next_file(&mut self) -> Result<bool, ReadError> {
+        let file_path = match self.files.pop_front() {
+            Some(file_path) => file_path,
+            None => return Ok(false),
+        };
 
-        let file_path_len = file_path.len();
-        let blk_index = file_path[file_path_len - 9..file_path_len - 4]
-            .parse::<u32>()
-            .unwrap();
+        // Continue from the current position if the file was already started
+        let index = blk_index(&file_path)?;
+        let offset = match self.position {
+            Some(position) if position.blk_index == index => position.offset,
+            _ => 0,
+        };
 
-        let mut offset = 0; // Buffer offset
+        debug!(file:% = file_path, offset; "Opening blk file");
+        let mut file = BlkFile::open(file_path, self.xor_key, offset, self.with_body())?;
+        file.last_height = self.height;
+        self.position = Some(BlockLocation {
+            blk_index: file.index,
+            offset: file.offset,
+        });
+        self.file = Some(file);
 
-        let mut reader = BufReader::new(file);
+        Ok(true)
+    }
 
-        loop {
-            let magic = Magic::consensus_decode(&mut reader).unwrap();
-            if magic != MAGIC {
-                println!(
-                    "Magic is not correct in {} offset={}; got {}",
-                    file_path, offset, magic
-                );
-                return Err(Error::new(ErrorKind::Other, "Magic is not correct"));
+    /// Read the next block from the current file and insert it into the index
+    /// Return true if there are more blocks to read, false if we should stop reading
+    fn read_block(&mut self) -> Result<bool, ReadError> {
+        let expected_magic = self.magic();
+        let with_body = self.with_body();
+
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return self.open_next_file(),
+        };
+
+        // End of file, there are more blocks to read in the next file
+        if file.offset >= file.size {
+            if let Some(ref file_cb) = self.file_cb {
+                file_cb(file.path.clone(), file.last_height, file.last_time);
             }
 
-            let size = u32::consensus_decode(&mut reader).unwrap() as usize;
+            self.file = None;
+            return Ok(true);
+        }
+
+        let record = match file.read_record(expected_magic, with_body) {
+            Err(err @ ReadError::Io { .. }) => return Err(err),
+            // The node is still writing the last block of the newest file,
+            // stop before it and read it again once it is complete
+            Err(ReadError::Truncated { .. }) if self.files.is_empty() => {
+                debug!(file:% = file.path, offset = file.offset; "Last block is not complete yet");
+                self.file = None;
+                return Ok(false);
+            }
+            Err(err) if self.options.recover => return self.skip_corrupt(expected_magic, &err),
+            record => record?,
+        };
 
-            // Read the block header
-            let header = Header::consensus_decode(&mut reader).unwrap();
+        let (size, header, data) = match record {
+            Some(record) => record,
+            None => {
+                // The rest of the file is preallocated, blocks will be written there later
+                if let Some(ref skip_cb) = self.skip_cb {
+                    skip_cb(SkippedRange {
+                        file: file.path.clone(),
+                        offset: file.offset,
+                        len: file.size - file.offset,
+                        reason: SkipReason::Preallocated,
+                    });
+                }
 
-            let time = header.time;
-            let height: u32 = self.height;
+                self.progress
+                    .set_preallocated(file.index, file.size - file.offset);
+                file.size = file.offset;
+                return Ok(true);
+            }
+        };
 
-            // Skip the rest of the block
-            let mut data = vec![0; size - 80];
-            reader.read_exact(&mut data).unwrap();
+        // The node may still be writing the last record of the newest file in its
+        // preallocated space, stop before it and read it again once it is complete
+        // In recovery mode, the transactions of the other records are checked too
+        let last = self.files.is_empty() && !file.is_followed(size)?;
+        if (last || (self.options.recover && data.is_some()))
+            && !file.is_written(size, &header, data.as_deref())?
+        {
+            if !last {
+                return self.skip_corrupt(expected_magic, &"transactions don't match the header");
+            }
 
-            // Insert the block into the index
-            self.insert(LazyBlock {
-                header,
-                data,
+            debug!(file:% = file.path, offset = file.offset; "Last block is not complete yet");
+            self.file = None;
+            return Ok(false);
+        }
+
+        let block = LazyBlock {
+            header,
+            body: Body::new(data, self.options.headers_only, file.xor_key),
+            size,
+            offset: file.offset,
+            blk_path: file.path.clone(),
+            blk_index: file.index,
+        };
+
+        let height: u32 = self.height;
+
+        file.offset += 4 + 4 + size as u64;
+        file.last_height = height;
+        file.last_time = header.time;
+
+        self.position = Some(BlockLocation {
+            blk_index: file.index,
+            offset: file.offset,
+        });
+
+        // Insert the block into the index
+        self.insert(block)?;
+        self.progress.block_read();
+
+        if self.progress.due(self.options.progress_interval) {
+            self.report_progress();
+        }
+
+        self.dump_requested()?;
+
+        // Stop signal received
+        if self.stopped() {
+            info!(height; "Stop signal received");
+            return Ok(false);
+        }
+
+        // We reached the limit of blocks, stop here
+        if self.max_height_reached() {
+            info!(height, next:% = self.chain.next_id(); "Reached limit of blocks");
+            return Ok(false);
+        }
+
+        // We reached the limit of orphan blocks, stop here
+        if self.max_orphans_reached() {
+            warn!(height, orphans = self.orphans(); "Reached limit of orphan blocks");
+            self.save_fork_tree()?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Skip the corrupt bytes at the offset of the current file and continue
+    /// with the next valid record
+    fn skip_corrupt(&mut self, magic: Magic, error: &dyn Display) -> Result<bool, ReadError> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(true),
+        };
+
+        let offset = file.offset;
+        file.resync(magic)?;
+        warn!(
+            file:% = file.path,
+            offset,
+            len = file.offset - offset,
+            error:% = error;
+            "Skipping corrupt record"
+        );
+
+        if let Some(ref skip_cb) = self.skip_cb {
+            skip_cb(SkippedRange {
+                file: file.path.clone(),
                 offset,
-                blk_path: file_path.to_string(),
-                blk_index,
+                len: file.offset - offset,
+                reason: SkipReason::Corrupt,
             });
+        }
 
-            offset += 4 + 4 + size as u64;
-
-            // Stop signal received
-            if self
-                .options
-                .stop_flag
-                .load(std::sync::atomic::Ordering::Relaxed)
-            {
-                println!("Stop signal received");
-                return Ok(false);
-            }
+        Ok(true)
+    }
 
-            // We reached the limit of blocks, stop here
-            if self.max_height_reached() {
-                println!(
-                    "Reached limit of blocks. Next block is {} {}",
-                    height,
-                    self.chain.next_id()
-                );
-                return Ok(false);
+    /// Insert a block into the index
+    fn insert(&mut self, block: LazyBlock) -> Result<(), ReadError> {
+        // Spilled orphans are inserted back once their parent is attached
+        let mut blocks = vec![block];
+        while let Some(block) = blocks.pop() {
+            // The index only knows the last block that left it
+            if self.recent_ids.contains(&block.get_block_id()) {
+                self.report_duplicate(Duplicate {
+                    first: None,
+                    duplicate: block,
+                });
+                continue;
             }
 
-            // We reached the limit of orphan blocks, stop here
-            if self.max_orphans_reached() {
-                println!("Reached limit of orphan blocks {}", self.orphans());
-                return Ok(false);
+            self.chain.insert(block);
+            self.spill_evicted()?;
+            for duplicate in self.chain.take_duplicates() {
+                self.report_duplicate(duplicate);
             }
 
-            // End of file, there are more blocks to read in the next file
-            if offset >= file_size {
-                if let Some(ref file_cb) = self.file_cb {
-                    file_cb(file_path.to_string(), height, time);
+            if let Some(ref mut spill) = self.spill {
+                for id in self.chain.take_attached() {
+                    for spilled in spill.take::<SpilledBlock>(&id)? {
+                        blocks.push(spilled.into_block(self.options.headers_only, self.xor_key)?);
+                    }
                 }
-
-                return Ok(true);
             }
         }
-    }
+        self.chain.take_attached();
 
-    /// Insert a block into the index
-    fn insert(&mut self, block: LazyBlock) {
-        self.chain.insert(block);
+        self.connect_best_chain();
 
-        while self.chain.longest_chain_depth() >= 10 {
-            match self.chain.pop_head() {
+        while self.chain.best_chain_depth() >= self.options.confirmation_depth {
+            match self.pop_head() {
                 Some(block) => {
-                    self.push_block(block);
+                    self.push_block(block)?;
                     if self.max_height_reached() {
-                        return;
+                        return Ok(());
                     }
                 }
-                None => return,
+                None => return Ok(()),
             }
         }
+
+        Ok(())
     }
 
-    fn push_block(&mut self, block: LazyBlock) {
-        let height = self.height;
+    fn report_duplicate(&mut self, duplicate: Duplicate<LazyBlock>) {
+        self.duplicates += 1;
+        debug!(
+            hash:% = duplicate.duplicate.get_block_id(),
+            file:% = duplicate.duplicate.blk_path,
+            offset = duplicate.duplicate.offset;
+            "Duplicate block"
+        );
 
-        self.height += 1;
+        if let Some(ref duplicate_cb) = self.duplicate_cb {
+            let location = |block: &LazyBlock| BlockLocation {
+                blk_index: block.blk_index,
+                offset: block.offset,
+            };
 
-        // Call the callback function
-        if let Some(ref block_cb) = self.block_cb {
-            block_cb(block, height);
+            duplicate_cb(DuplicateBlock {
+                hash: duplicate.duplicate.get_block_id(),
+                first: duplicate.first.as_ref().map(location),
+                duplicate: location(&duplicate.duplicate),
+            });
         }
     }
 
-    pub fn read(&mut self, dir_path: &std::path::Path) -> Result<(), Error> {
-        let entries = BlockReader::read_dir(&self, dir_path)?;
+    /// Write the orphans evicted from the index to the spill file, or drop them
+    fn spill_evicted(&mut self) -> Result<(), ReadError> {
+        for block in self.chain.take_evicted() {
+            let spill_dir = match self.options.spill_dir {
+                Some(ref spill_dir) => spill_dir,
+                None => {
+                    debug!(hash:% = block.get_block_id(); "Dropping evicted orphan");
+                    self.dropped_orphans += 1;
+                    continue;
+                }
+            };
 
-        for entry in entries {
-            if self.max_height_reached() {
-                break;
-            }
+            let spill = match self.spill {
+                Some(ref mut spill) => spill,
+                None => self.spill.insert(OrphanSpill::create(spill_dir)?),
+            };
 
-            if !self.read_blocs(&entry)? {
-                break;
-            }
+            let location = BlockLocation {
+                blk_index: block.blk_index,
+                offset: block.offset,
+            };
+            spill.push(
+                block.get_block_prev_id(),
+                location,
+                &SpilledBlock::new(block),
+            )?;
         }
 
         Ok(())
     }
 
-    /// Return the number of orphans blocks
-    pub fn orphans(&self) -> usize {
-        self.chain.orphans()
+    /// Remove the head of the index and report the branches it prunes
+    fn pop_head(&mut self) -> Option<LazyBlock> {
+        let block = self.chain.pop_head()?;
+
+        // The popped block is the fork point of the pruned branches
+        let fork_height = self.height;
+        for branch in self.chain.take_stale() {
+            self.report_stale(fork_height, branch);
+        }
+
+        Some(block)
     }
 
-    /// Return the height of the last block
-    pub fn height(&self) -> u32 {
-        self.height
+    fn report_stale(&mut self, fork_height: u32, branch: StaleBranch<BlockHash, LazyBlock>) {
+        let fork_point = branch.fork_point;
+        let branch_len = branch
+            .blocks
+            .iter()
+            .map(|(distance, _)| *distance)
+            .max()
+            .unwrap_or(0);
+
+        self.forks.push(ForkReport {
+            fork_point,
+            fork_height,
+            branch_len,
+            blocks: branch
+                .blocks
+                .iter()
+                .map(|(_, block)| block.get_block_id())
+                .collect(),
+        });
+
+        if let Some(ref stale_cb) = self.stale_cb {
+            for (distance, block) in branch.blocks {
+                stale_cb(StaleBlock {
+                    block,
+                    height: fork_height + distance,
+                    fork_point,
+                    fork_height,
+                    branch_len,
+                });
+            }
+        }
     }
 
-    fn max_height_reached(&self) -> bool {
-        match self.options.max_blocks {
-            Some(max_blocks) => self.height >= max_blocks,
-            None => false,
+    /// Report the changes of the best chain since the last insertion
+    fn connect_best_chain(&mut self) {
+        let event_cb = match self.event_cb {
+            Some(ref event_cb) => event_cb,
+            None => return,
+        };
+
+        // Blocks are reported once the start block is delivered
+        if !self.started(self.height) {
+            return;
+        }
+
+        let mut best = self.chain.best_chain();
+        if let Some(max_blocks) = self.options.max_blocks {
+            best.truncate(max_blocks.saturating_sub(self.height) as usize);
+        }
+
+        let common = self
+            .connected
+            .iter()
+            .zip(best.iter())
+            .take_while(|(connected, best)| connected == best)
+            .count();
+
+        // Disconnect from the tip down to the fork point
+        for (i, id) in self.connected.iter().enumerate().skip(common).rev() {
+            if let Some(block) = self.chain.get(id) {
+                event_cb(BlockEvent::BlockDisconnected(
+                    self.height + i as u32,
+                    block.clone(),
+                ));
+            }
+        }
+        self.connected.truncate(common);
+
+        for (i, id) in best.iter().enumerate().skip(common) {
+            if let Some(block) = self.chain.get(id) {
+                event_cb(BlockEvent::BlockConnected(
+                    self.height + i as u32,
+                    block.clone(),
+                ));
+            }
+            self.connected.push_back(*id);
         }
     }
 
-    fn max_orphans_reached(&self) -> bool {
-        match self.options.max_orphans {
-            Some(max_orphans) => self.orphans() >= max_orphans,
-            None => false,
+    /// Queue a block that left the index, it is now in its final position
+    fn push_block(&mut self, mut block: LazyBlock) -> Result<(), ReadError> {
+        let height = self.height;
+
+        // Only the genesis block of the network can build on the null hash
+        if height == 0 && block.get_block_id() != self.genesis_hash {
+            return Err(ReadError::BadGenesis {
+                found: block.get_block_id(),
+                file: block.blk_path,
+                offset: block.offset,
+                expected: self.genesis_hash,
+            });
+        }
+
+        self.height += 1;
+        self.tip_hash = block.get_block_id();
+        self.remember(self.tip_hash);
+
+        if self.waiting_for == Some(self.tip_hash) {
+            self.waiting_for = None;
         }
+
+        // Skip the blocks before the start block
+        if !self.started(height) {
+            return Ok(());
+        }
+
+        // The block was read before the start point, without its transactions
+        if self.keep_body() {
+            block.load_body()?;
+        }
+
+        if let Some(ref event_cb) = self.event_cb {
+            if self.connected.front() == Some(&block.get_block_id()) {
+                self.connected.pop_front();
+            } else {
+                event_cb(BlockEvent::BlockConnected(height, block.clone()));
+            }
+        }
+
+        self.ready.push_back((height, block));
+        Ok(())
+    }
+
+    /// Keep the hash of a block that left the index, forgetting the oldest
+    /// ones past `duplicate_window`
+    fn remember(&mut self, hash: BlockHash) {
+        if self.options.duplicate_window == 0 {
+            return;
+        }
+
+        self.recent.push_back(hash);
+        self.recent_ids.insert(hash);
+        if self.recent.len() > self.options.duplicate_window {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.recent_ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Prepare the reader to read the blk files of a directory
+    fn open(&mut self, dir_path: &Path) -> Result<(), ReadError> {
+        // Bitcoin Core 28+ obfuscates blk files with the key stored in xor.dat
+        self.xor_key = match self.options.xor_key {
+            Some(xor_key) => xor_key,
+            None => read_xor_key(dir_path)?.unwrap_or_default(),
+        };
+
+        self.dir_path = dir_path.to_path_buf();
+        self.files = self.unread_files()?.into();
+        self.file = None;
+        self.done = false;
+        self.progress.set_file_sizes(self.file_sizes()?);
+        self.progress.start(self.position);
+
+        // Read again the blocks that were not delivered when the checkpoint was made
+        let magic = self.magic();
+        for location in std::mem::take(&mut self.pending) {
+            let file_path = dir_path
+                .join(format!("blk{:05}.dat", location.blk_index))
+                .to_string_lossy()
+                .to_string();
+            let mut file =
+                BlkFile::open(file_path, self.xor_key, location.offset, self.with_body())?;
+            let (size, header, data) = match file.read_record(magic, self.with_body())? {
+                Some(record) => record,
+                None => continue,
+            };
+
+            self.insert(LazyBlock {
+                header,
+                body: Body::new(data, self.options.headers_only, file.xor_key),
+                size,
+                offset: location.offset,
+                blk_path: file.path,
+                blk_index: file.index,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the size of every blk file of the directory by index
+    fn file_sizes(&self) -> Result<BTreeMap<u32, u64>, ReadError> {
+        self.read_dir(&self.dir_path)?
+            .into_iter()
+            .map(|file| {
+                let metadata = fs::metadata(&file).map_err(|err| ReadError::io(&file, err))?;
+                Ok((blk_index(&file)?, metadata.len()))
+            })
+            .collect()
+    }
+
+    /// List the files of the directory with blocks after the current position
+    fn unread_files(&self) -> Result<Vec<String>, ReadError> {
+        let mut files = self.read_dir(&self.dir_path)?;
+
+        // Skip the files already read
+        if let Some(position) = self.position {
+            files.retain(|file| match blk_index(file) {
+                Ok(index) if index == position.blk_index => has_record_at(file, position.offset),
+                Ok(index) => index > position.blk_index,
+                Err(_) => true,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Wait for new blocks to be written to the directory
+    /// Return false if the stop signal was received while waiting
+    fn wait_for_files(&mut self) -> Result<bool, ReadError> {
+        loop {
+            if self.stopped() {
+                return Ok(false);
+            }
+
+            self.dump_requested()?;
+
+            // Wait before checking, an incomplete block may still be in the files
+            std::thread::sleep(self.options.poll_interval);
+
+            let files = self.unread_files()?;
+            if !files.is_empty() {
+                self.files = files.into();
+                self.progress.set_file_sizes(self.file_sizes()?);
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Return the state needed to resume reading from the next block to deliver
+    pub fn checkpoint(&self) -> Checkpoint {
+        // Blocks waiting to be delivered are read again on resume
+        let mut pending: Vec<BlockLocation> = self
+            .ready
+            .iter()
+            .map(|(_, block)| BlockLocation {
+                blk_index: block.blk_index,
+                offset: block.offset,
+            })
+            .collect();
+        self.chain.for_each(|block| {
+            pending.push(BlockLocation {
+                blk_index: block.blk_index,
+                offset: block.offset,
+            })
+        });
+        if let Some(ref spill) = self.spill {
+            pending.extend(spill.locations());
+        }
+
+        let best_hash = match self.ready.front() {
+            Some((_, block)) => block.header.prev_blockhash,
+            None => self.tip_hash,
+        };
+
+        Checkpoint {
+            height: self.height - self.ready.len() as u32,
+            best_hash: best_hash.to_byte_array(),
+            position: self.position.unwrap_or(BlockLocation {
+                blk_index: 0,
+                offset: 0,
+            }),
+            pending,
+            start_hash: self.waiting_for.map(|hash| hash.to_byte_array()),
+        }
+    }
+
+    fn save_checkpoint(&mut self) -> Result<(), ReadError> {
+        if let Some(ref checkpoint_path) = self.options.checkpoint_path {
+            self.checkpoint().save(checkpoint_path)?;
+            self.checkpoint_height = self.height;
+        }
+
+        Ok(())
+    }
+
+    /// Save the fork tree if the dump flag is set
+    fn dump_requested(&self) -> Result<(), ReadError> {
+        if self
+            .options
+            .dump_flag
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            self.save_fork_tree()?;
+        }
+
+        Ok(())
+    }
+
+    fn save_fork_tree(&self) -> Result<(), ReadError> {
+        match self.options.fork_tree_path {
+            Some(ref fork_tree_path) => self.fork_tree().save(fork_tree_path),
+            None => Ok(()),
+        }
+    }
+
+    fn report_progress(&mut self) {
+        if self.progress_cb.is_some() {
+            let state = self.progress_state();
+            let progress = self.progress.report(state);
+            if let Some(ref progress_cb) = self.progress_cb {
+                progress_cb(progress);
+            }
+        }
+    }
+
+    fn progress_state(&self) -> ProgressState {
+        let ready_mem: usize = self
+            .ready
+            .iter()
+            .map(|(_, block)| block.get_block_mem_size())
+            .sum();
+
+        // Each recent hash is stored in the queue and in the set
+        let recent_mem = self.recent.len() * 2 * std::mem::size_of::<BlockHash>();
+
+        ProgressState {
+            position: self.position,
+            height: self.height,
+            orphans: self.chain.orphans(),
+            orphans_mem: self.chain.orphans_mem(),
+            mem: self.chain.mem() + ready_mem + recent_mem,
+        }
+    }
+
+    fn checkpoint_due(&self) -> bool {
+        match self.options.checkpoint_interval {
+            Some(interval) => self.height >= self.checkpoint_height + interval,
+            None => false,
+        }
+    }
+
+    /// Return the next block in chain order with its height
+    /// Return None once all files are read or a limit is reached
+    fn next_block(&mut self) -> Result<Option<(u32, LazyBlock)>, ReadError> {
+        loop {
+            if let Some(block) = self.ready.pop_front() {
+                return Ok(Some(block));
+            }
+
+            if self.done {
+                // Every block was delivered, the checkpoint is up to date
+                self.save_checkpoint()?;
+                self.report_progress();
+                return Ok(None);
+            }
+
+            if self.checkpoint_due() {
+                self.save_checkpoint()?;
+            }
+
+            if !self.read_block()? {
+                let end_of_input = self.file.is_none() && self.files.is_empty();
+
+                // All files were read, wait for the node to write more blocks
+                if end_of_input && self.options.follow {
+                    if let Some(hash) = self.start_not_found() {
+                        warn!(hash:%; "Start block not found yet");
+                    }
+
+                    if self.wait_for_files()? {
+                        continue;
+                    }
+                }
+
+                self.done = true;
+
+                // All files were read, nothing will bury the remaining blocks
+                if end_of_input && self.options.flush_tip && !self.options.follow {
+                    self.flush()?;
+                }
+
+                // Nothing was or will be delivered
+                if end_of_input && !self.options.follow {
+                    if let Some(hash) = self.start_not_found() {
+                        return Err(ReadError::StartNotFound { hash });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deliver the remaining blocks of the longest chain
+    fn flush(&mut self) -> Result<(), ReadError> {
+        while !self.max_height_reached() {
+            match self.pop_head() {
+                Some(block) => {
+                    self.unburied_height.get_or_insert(self.height);
+                    self.push_block(block)?;
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read all blocks of the directory, calling the block callback in chain order
+    pub fn read(&mut self, dir_path: &Path) -> Result<(), ReadError> {
+        self.open(dir_path)?;
+
+        while let Some((height, block)) = self.next_block()? {
+            if let Some(ref block_cb) = self.block_cb {
+                block_cb(block, height);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Iterate over the blocks of the directory in chain order
+    /// Blocks are yielded with their height instead of being passed to the block callback
+    pub fn blocks<'r>(&'r mut self, dir_path: &Path) -> Blocks<'r, 'a> {
+        Blocks {
+            reader: self,
+            dir_path: Some(dir_path.to_path_buf()),
+            done: false,
+        }
+    }
+
+    /// Return the progress of the read since the directory was opened
+    pub fn progress(&self) -> Progress {
+        self.progress.progress(self.progress_state())
+    }
+
+    /// Return a snapshot of the blocks waiting to be delivered and of the orphans
+    pub fn fork_tree(&self) -> ForkTree {
+        self.chain.fork_tree()
+    }
+
+    /// Return the number of orphans blocks
+    pub fn orphans(&self) -> usize {
+        self.chain.orphans()
+    }
+
+    /// Return the memory held by the orphan blocks
+    pub fn orphans_mem(&self) -> usize {
+        self.chain.orphans_mem()
+    }
+
+    /// Return the number of orphan blocks written to the spill file
+    pub fn spilled_orphans(&self) -> usize {
+        self.spill.as_ref().map_or(0, OrphanSpill::len)
+    }
+
+    /// Return the number of orphan blocks evicted without a spill directory
+    pub fn dropped_orphans(&self) -> u64 {
+        self.dropped_orphans
+    }
+
+    /// Return the number of blocks found more than once in the blk files
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Return the height of the last block
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Return the forks found so far, in the order they were resolved
+    pub fn forks(&self) -> &[ForkReport] {
+        &self.forks
+    }
+
+    /// Return the height of the first block delivered without being buried,
+    /// if the tip was flushed. Blocks from this height may be reorganized
+    pub fn unburied_height(&self) -> Option<u32> {
+        self.unburied_height
+    }
+
+    fn stopped(&self) -> bool {
+        self.options
+            .stop_flag
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Return true if the block at this height should be delivered
+    fn started(&self, height: u32) -> bool {
+        self.waiting_for.is_none() && height >= self.options.start_height.unwrap_or(0)
+    }
+
+    /// Return true if the transactions must be read with the header
+    /// Before the start point, they are only read for the blocks that get delivered
+    fn with_body(&self) -> bool {
+        self.keep_body() && self.started(self.height)
+    }
+
+    /// Return true if the delivered blocks hold their transactions in memory
+    fn keep_body(&self) -> bool {
+        !self.options.headers_only && !self.options.lazy_body
+    }
+
+    /// Return the start hash if it is not part of the best chain read so far
+    fn start_not_found(&self) -> Option<BlockHash> {
+        let hash = self.waiting_for?;
+        if self.chain.best_chain().contains(&hash) {
+            None
+        } else {
+            Some(hash)
+        }
+    }
+
+    /// Return the magic expected at the beginning of each block
+    fn magic(&self) -> Magic {
+        self.options.magic.unwrap_or(self.options.network.magic())
+    }
+
+    fn max_height_reached(&self) -> bool {
+        match self.options.max_blocks {
+            Some(max_blocks) => self.height >= max_blocks,
+            None => false,
+        }
+    }
+
+    fn max_orphans_reached(&self) -> bool {
+        match self.options.max_orphans {
+            Some(max_orphans) => self.orphans() >= max_orphans,
+            None => false,
+        }
+    }
+}
+
+/// Iterator over the blocks of a directory, see `BlockReader::blocks`
+pub struct Blocks<'r, 'call> {
+    reader: &'r mut BlockReader<'call>,
+    // Directory to open on the first call to next
+    dir_path: Option<PathBuf>,
+    done: bool,
+}
+
+impl Iterator for Blocks<'_, '_> {
+    type Item = Result<(u32, LazyBlock), ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if let Some(dir_path) = self.dir_path.take() {
+            if let Err(err) = self.reader.open(&dir_path) {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+
+        match self.reader.next_block() {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Version;
+    use bitcoin::script::PushBytesBuf;
+    use bitcoin::transaction;
+    use bitcoin::Amount;
+    use bitcoin::CompactTarget;
+    use bitcoin::OutPoint;
+    use bitcoin::ScriptBuf;
+    use bitcoin::Sequence;
+    use bitcoin::TxIn;
+    use bitcoin::TxMerkleNode;
+    use bitcoin::TxOut;
+    use bitcoin::Witness;
+
+    /// Build a block on top of `prev` with only a segwit coinbase, like the blocks
+    /// mined by Bitcoin Core when the mempool is empty
+    /// The coinbase ends with its zero witness reserved value and lock time
+    fn coinbase_block(prev: &Block, time: u32) -> Block {
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::builder().push_int(time as i64).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[0; 32]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50 * 100_000_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase],
+        };
+
+        let commitment =
+            Block::compute_witness_commitment(&block.witness_root().unwrap(), &[0; 32]);
+        let mut push = PushBytesBuf::from([0xaa, 0x21, 0xa9, 0xed]);
+        push.extend_from_slice(commitment.as_ref()).unwrap();
+        block.txdata[0].output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(push),
+        });
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    /// Build a chain of regtest blocks starting from the genesis block
+    fn build_chain(len: u32) -> Vec<Block> {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for i in 1..len {
+            let block = coinbase_block(blocks.last().unwrap(), i);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Build a block on top of `prev` that competes with its other children
+    fn stale_block(prev: &Block) -> Block {
+        coinbase_block(prev, u32::MAX)
+    }
+
+    /// Serialize blocks as they are stored in a blk file
+    fn blk_data(blocks: &[&Block]) -> Vec<u8> {
+        let mut data = vec![];
+        for block in blocks {
+            let block = serialize(*block);
+            data.extend(Magic::REGTEST.to_bytes());
+            data.extend((block.len() as u32).to_le_bytes());
+            data.extend(block);
+        }
+        data
+    }
+
+    /// Temporary blocks directory, removed when dropped
+    struct BlocksDir(PathBuf);
+
+    impl std::ops::Deref for BlocksDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for BlocksDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Create a temporary blocks directory containing the given blk files
+    fn blocks_dir(name: &str, files: &[Vec<u8>]) -> BlocksDir {
+        let dir = std::env::temp_dir().join(format!("blk-reader-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (i, data) in files.iter().enumerate() {
+            let mut file = File::create(dir.join(format!("blk{:05}.dat", i))).unwrap();
+            file.write_all(data).unwrap();
+        }
+        BlocksDir(dir)
+    }
+
+    /// Poll the condition for up to 5 seconds, return true once it holds
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..500 {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Read a directory in follow mode from another thread while `update` writes to it
+    /// Return the heights of the blocks read, up to `count` or until the stop flag is set
+    fn follow_dir(
+        dir: &Path,
+        mut options: BlockReaderOptions,
+        checkpoint: Option<Checkpoint>,
+        count: usize,
+        update: impl FnOnce(),
+    ) -> Vec<u32> {
+        options.follow = true;
+        options.poll_interval = Duration::from_millis(10);
+        let stop_flag = Arc::clone(&options.stop_flag);
+
+        let reader_dir = dir.to_path_buf();
+        let handle = std::thread::spawn(move || {
+            let mut reader = match checkpoint {
+                Some(checkpoint) => BlockReader::resume(options, checkpoint),
+                None => BlockReader::new(options),
+            };
+            reader
+                .blocks(&reader_dir)
+                .take(count)
+                .map(|block| block.unwrap().0)
+                .collect::<Vec<_>>()
+        });
+
+        // Let the reader reach the end of the files before they change
+        std::thread::sleep(Duration::from_millis(50));
+        update();
+
+        wait_for(|| handle.is_finished());
+        stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        handle.join().unwrap()
+    }
+
+    fn regtest_options() -> BlockReaderOptions {
+        BlockReaderOptions {
+            network: Network::Regtest,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_genesis_stored_late() {
+        let chain = build_chain(20);
+
+        // Genesis is stored after enough blocks to bury the first one
+        let mut blocks = chain[1..13].iter().collect::<Vec<_>>();
+        blocks.push(&chain[0]);
+        blocks.extend(chain[13..].iter());
+        let dir = blocks_dir("genesis-late", &[blk_data(&blocks)]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let blocks = reader.blocks(&dir).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(blocks.len(), 20);
+        for (i, (height, block)) in blocks.iter().enumerate() {
+            assert_eq!(*height, i as u32);
+            assert_eq!(block.header.block_hash(), chain[i].block_hash());
+        }
+        assert_eq!(reader.duplicates(), 0);
+
+        // The chain must start with the genesis block of the network
+        let mainnet_genesis = genesis_block(Network::Bitcoin);
+        let dir = blocks_dir("genesis-bad", &[blk_data(&[&mainnet_genesis])]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let result = reader.blocks(&dir).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(result, Err(ReadError::BadGenesis { .. })));
+    }
+
+    #[test]
+    fn test_blocks_iterator() {
+        let chain = build_chain(16);
+
+        // Blocks are not stored in order, some are in the next file
+        let mut first = chain.iter().collect::<Vec<_>>();
+        let second = first.split_off(12);
+        first.swap(3, 5);
+        let dir = blocks_dir("iterator", &[blk_data(&first), blk_data(&second)]);
+
+        let mut reader = BlockReader::new(regtest_options());
+        let blocks = reader.blocks(&dir).collect::<Result<Vec<_>, _>>().unwrap();
+
+        // The last blocks are kept in the index until they are buried
+        assert_eq!(blocks.len(), 7);
+        for (i, (height, block)) in blocks.iter().enumerate() {
+            assert_eq!(*height, i as u32);
+            assert_eq!(block.header, chain[i].header);
+        }
+
+        // Iteration can be stopped early
+        let mut reader = BlockReader::new(regtest_options());
+        let heights = reader
+            .blocks(&dir)
+            .take(2)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_flush_tip() {
+        let chain = build_chain(16);
+        let dir = blocks_dir("flush", &[blk_data(&chain.iter().collect::<Vec<_>>())]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+
+        assert_eq!(heights, (0..16).collect::<Vec<_>>());
+        assert_eq!(reader.unburied_height(), Some(7));
+    }
+
+    #[test]
+    fn test_block_events() {
+        let chain = build_chain(12);
+        let stale = stale_block(&chain[4]);
+
+        // The stale block is read first and temporarily connected
+        let mut blocks = chain.iter().collect::<Vec<_>>();
+        blocks.insert(5, &stale);
+        let dir = blocks_dir("events", &[blk_data(&blocks)]);
+
+        let events = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(regtest_options());
+        reader.set_event_cb(Box::new(|event| {
+            events.lock().unwrap().push(match event {
+                BlockEvent::BlockConnected(height, block) => (true, height, block.header),
+                BlockEvent::BlockDisconnected(height, block) => (false, height, block.header),
+            });
+        }));
+        reader.read(&dir).unwrap();
+        drop(reader);
+
+        let events = events.into_inner().unwrap();
+        let mut expected = (0..6)
+            .map(|i| (true, i, chain[i as usize].header))
+            .collect::<Vec<_>>();
+        expected[5] = (true, 5, stale.header);
+        expected.push((false, 5, stale.header));
+        expected.extend((5..12).map(|i| (true, i, chain[i as usize].header)));
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn test_stale_blocks() {
+        let chain = build_chain(12);
+        let stale = stale_block(&chain[4]);
+
+        let mut blocks = chain.iter().collect::<Vec<_>>();
+        blocks.push(&stale);
+        let dir = blocks_dir("stale", &[blk_data(&blocks)]);
+
+        let stale_blocks = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        reader.set_stale_cb(Box::new(|stale| {
+            stale_blocks.lock().unwrap().push(stale);
+        }));
+        reader.read(&dir).unwrap();
+
+        let forks = reader.forks();
+        assert_eq!(forks.len(), 1);
+        assert_eq!(forks[0].fork_point, chain[4].block_hash());
+        assert_eq!(forks[0].fork_height, 4);
+        assert_eq!(forks[0].branch_len, 1);
+        assert_eq!(forks[0].blocks, vec![stale.block_hash()]);
+        drop(reader);
+
+        let stale_blocks = stale_blocks.into_inner().unwrap();
+        assert_eq!(stale_blocks.len(), 1);
+        assert_eq!(stale_blocks[0].height, 5);
+        assert_eq!(stale_blocks[0].block.header, stale.header);
+    }
+
+    #[test]
+    fn test_out_of_order_fork() {
+        let chain = build_chain(12);
+        let stale = stale_block(&chain[4]);
+
+        // Both children of the fork point are read before it
+        let mut blocks = chain.iter().collect::<Vec<_>>();
+        blocks.insert(4, &stale);
+        blocks.swap(5, 6);
+        let dir = blocks_dir("out-of-order-fork", &[blk_data(&blocks)]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+
+        assert_eq!(heights, (0..12).collect::<Vec<_>>());
+        assert_eq!(reader.forks().len(), 1);
+        assert_eq!(reader.forks()[0].blocks, vec![stale.block_hash()]);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let chain = build_chain(16);
+
+        // A block still in the index is stored again in the next file
+        let first = blk_data(&chain.iter().collect::<Vec<_>>());
+        let second = blk_data(&[&chain[14]]);
+        let dir = blocks_dir("duplicates", &[first, second]);
+
+        let duplicates = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        reader.set_duplicate_cb(Box::new(|duplicate| {
+            duplicates.lock().unwrap().push(duplicate);
+        }));
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (0..16).collect::<Vec<_>>());
+        assert_eq!(reader.duplicates(), 1);
+        assert!(reader.forks().is_empty());
+        drop(reader);
+
+        let duplicates = duplicates.into_inner().unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].hash, chain[14].block_hash());
+        assert_eq!(duplicates[0].first.unwrap().blk_index, 0);
+        assert_eq!(
+            duplicates[0].duplicate,
+            BlockLocation {
+                blk_index: 1,
+                offset: 0
+            }
+        );
+
+        // A block delivered long before is stored again
+        let chain = build_chain(40);
+        let mut blocks = chain.iter().collect::<Vec<_>>();
+        blocks.push(&chain[3]);
+        let dir = blocks_dir("old-duplicates", &[blk_data(&blocks)]);
+
+        let mut reader = BlockReader::new(regtest_options());
+        assert_eq!(reader.blocks(&dir).count(), 31);
+        assert_eq!(reader.duplicates(), 1);
+        assert_eq!(reader.orphans(), 0);
+
+        // Only the hashes of the last blocks are kept
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            duplicate_window: 20,
+            ..regtest_options()
+        });
+        assert_eq!(reader.blocks(&dir).count(), 31);
+        assert_eq!(reader.recent.len(), 20);
+        assert_eq!(reader.duplicates(), 0);
+        assert_eq!(reader.orphans(), 1);
+    }
+
+    #[test]
+    fn test_progress() {
+        let chain = build_chain(20);
+        let mut first = chain.iter().collect::<Vec<_>>();
+        let second = first.split_off(12);
+        let mut files = [blk_data(&first), blk_data(&second)];
+        let total_bytes: u64 = files.iter().map(|file| file.len() as u64).sum();
+
+        // The newest file is preallocated, its zeros are not counted
+        files[1].extend([0; 4096]);
+        let dir = blocks_dir("progress", &files);
+
+        let reports = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            progress_interval: Duration::ZERO,
+            ..regtest_options()
+        });
+        reader.set_progress_cb(Box::new(|progress| {
+            reports.lock().unwrap().push(progress);
+        }));
+        assert_eq!(reader.blocks(&dir).count(), 20);
+        assert_eq!(reader.progress().bytes_read, total_bytes);
+        drop(reader);
+
+        // One report per block read and a last one once every block is delivered
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.len(), 21);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].bytes_read <= pair[1].bytes_read));
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.total_bytes, total_bytes);
+        assert_eq!(last.ratio(), 1.0);
+        assert_eq!(last.blocks_read, 20);
+        assert_eq!(last.height, 20);
+        assert_eq!(last.orphans, 0);
+        // Only the hashes of the blocks delivered are left
+        assert_eq!(last.mem, 20 * 2 * std::mem::size_of::<BlockHash>());
+    }
+
+    #[test]
+    fn test_resume() {
+        let chain = build_chain(30);
+        let mut first = chain.iter().collect::<Vec<_>>();
+        let second = first.split_off(20);
+        let dir = blocks_dir("resume", &[blk_data(&first), blk_data(&second)]);
+        let checkpoint_path = dir.join("checkpoint.bin");
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            max_blocks: Some(12),
+            checkpoint_path: Some(checkpoint_path.clone()),
+            ..regtest_options()
+        });
+        let mut heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights.len(), 12);
+
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        assert_eq!(checkpoint.height, 12);
+        assert_eq!(checkpoint.best_hash, chain[11].block_hash().to_byte_array());
+
+        let mut reader = BlockReader::resume(
+            BlockReaderOptions {
+                max_blocks: None,
+                flush_tip: true,
+                ..regtest_options()
+            },
+            checkpoint,
+        );
+        for block in reader.blocks(&dir) {
+            let (height, block) = block.unwrap();
+            assert_eq!(block.header, chain[height as usize].header);
+            heights.push(height);
+        }
+        assert_eq!(heights, (0..30).collect::<Vec<_>>());
+
+        // The blocks of the checkpoint are no longer in the files
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        fs::write(dir.join("blk00000.dat"), blk_data(&first[..2])).unwrap();
+        let mut reader = BlockReader::resume(regtest_options(), checkpoint);
+        let result = reader.blocks(&dir).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(result, Err(ReadError::BadOffset { .. })));
+    }
+
+    #[test]
+    fn test_start() {
+        let chain = build_chain(20);
+        let dir = blocks_dir("start", &[blk_data(&chain.iter().collect::<Vec<_>>())]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            start_height: Some(5),
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let blocks = reader.blocks(&dir).collect::<Result<Vec<_>, _>>().unwrap();
+        let heights = blocks.iter().map(|(height, _)| *height).collect::<Vec<_>>();
+        assert_eq!(heights, (5..20).collect::<Vec<_>>());
+        assert!(blocks
+            .iter()
+            .all(|(_, block)| matches!(block.body, Body::Loaded(_))));
+
+        // The transactions are not read before the start point
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            start_height: Some(15),
+            ..regtest_options()
+        });
+        assert_eq!(reader.blocks(&dir).count(), 0);
+        let block = reader.chain.get(&chain[12].block_hash()).unwrap();
+        assert!(matches!(block.body, Body::OnDisk(_)));
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            start_hash: Some(chain[8].block_hash()),
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (8..20).collect::<Vec<_>>());
+
+        // The start block is not part of the best chain
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            start_hash: Some(stale_block(&chain[8]).block_hash()),
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let result = reader.blocks(&dir).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(result, Err(ReadError::StartNotFound { .. })));
+
+        // A checkpoint made before the start block keeps waiting for it
+        let checkpoint_path = dir.join("checkpoint.bin");
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            max_blocks: Some(12),
+            start_hash: Some(chain[15].block_hash()),
+            checkpoint_path: Some(checkpoint_path.clone()),
+            ..regtest_options()
+        });
+        assert_eq!(reader.blocks(&dir).count(), 0);
+
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        assert_eq!(
+            checkpoint.start_hash,
+            Some(chain[15].block_hash().to_byte_array())
+        );
+        let mut reader = BlockReader::resume(
+            BlockReaderOptions {
+                flush_tip: true,
+                ..regtest_options()
+            },
+            checkpoint,
+        );
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (15..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_follow() {
+        let chain = build_chain(30);
+        let dir = blocks_dir(
+            "follow",
+            &[blk_data(&chain[..10].iter().collect::<Vec<_>>())],
+        );
+
+        // The node appends blocks to the last file, then starts a new one
+        let heights = follow_dir(&dir, regtest_options(), None, 15, || {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join("blk00000.dat"))
+                .unwrap();
+            file.write_all(&blk_data(&chain[10..20].iter().collect::<Vec<_>>()))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            let mut file = File::create(dir.join("blk00001.dat")).unwrap();
+            file.write_all(&blk_data(&chain[20..].iter().collect::<Vec<_>>()))
+                .unwrap();
+        });
+        assert_eq!(heights, (0..15).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_follow_partial_record() {
+        let chain = build_chain(21);
+        let record = blk_data(&[&chain[10]]);
+
+        // The node wrote the magic and size of block 10 in the preallocated space,
+        // the block itself is not written yet
+        let mut data = blk_data(&chain[..10].iter().collect::<Vec<_>>());
+        let start = data.len() as u64;
+        data.extend(&record[..8]);
+        data.resize(data.len() + 4096, 0);
+        let dir = blocks_dir("follow-partial", &[data]);
+
+        for (headers_only, lazy_body) in [(false, false), (true, false), (false, true)] {
+            let mut reader = BlockReader::new(BlockReaderOptions {
+                flush_tip: true,
+                headers_only,
+                lazy_body,
+                ..regtest_options()
+            });
+            assert_eq!(reader.blocks(&dir).count(), 10);
+            assert_eq!(reader.orphans(), 0);
+            assert_eq!(reader.checkpoint().position.offset, start);
+        }
+
+        // The node writes the rest of block 10, then the next blocks
+        let heights = follow_dir(&dir, regtest_options(), None, 11, || {
+            let mut rest = record[8..].to_vec();
+            rest.extend(blk_data(&chain[11..].iter().collect::<Vec<_>>()));
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(dir.join("blk00000.dat"))
+                .unwrap();
+            file.seek(SeekFrom::Start(start + 8)).unwrap();
+            file.write_all(&rest).unwrap();
+        });
+        assert_eq!(heights, (0..11).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_coinbase_only_blocks() {
+        let chain = build_chain(30);
+        // The record of a coinbase only block ends with zeros
+        assert!(serialize(&chain[1]).ends_with(&[0; 36]));
+        let dir = blocks_dir(
+            "coinbase-only",
+            &[blk_data(&chain.iter().collect::<Vec<_>>())],
+        );
+
+        for (headers_only, lazy_body) in [(false, false), (true, false), (false, true)] {
+            let mut reader = BlockReader::new(BlockReaderOptions {
+                flush_tip: true,
+                headers_only,
+                lazy_body,
+                ..regtest_options()
+            });
+            let heights = reader
+                .blocks(&dir)
+                .map(|block| block.unwrap().0)
+                .collect::<Vec<_>>();
+            assert_eq!(heights, (0..30).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_headers_only() {
+        let chain = build_chain(16);
+        let dir = blocks_dir("headers", &[blk_data(&chain.iter().collect::<Vec<_>>())]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            headers_only: true,
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let blocks = reader.blocks(&dir).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(blocks.len(), 16);
+        for (height, block) in blocks {
+            assert_eq!(block.header, chain[height as usize].header);
+            assert_eq!(
+                block.size as usize,
+                serialize(&chain[height as usize]).len()
+            );
+            assert!(matches!(block.decode(), Err(ReadError::NoBody { .. })));
+        }
+
+        // Only the magic, the size and the header are buffered
+        let path = dir.join("blk00000.dat").to_string_lossy().to_string();
+        let file = BlkFile::open(path, XorKey::default(), 0, false).unwrap();
+        assert_eq!(file.reader.capacity(), 8 + Header::SIZE);
+    }
+
+    #[test]
+    fn test_lazy_body() {
+        let key: XorKey = [1, 2, 3, 4, 5, 6, 7, 8];
+        let chain = build_chain(16);
+
+        let mut data = blk_data(&chain.iter().collect::<Vec<_>>());
+        xor(&mut data, &key, 0);
+        let dir = blocks_dir("lazy", &[data]);
+        fs::write(dir.join("xor.dat"), key).unwrap();
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            lazy_body: true,
+            flush_tip: true,
+            ..regtest_options()
+        });
+        let blocks = reader.blocks(&dir).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(blocks.len(), 16);
+        for (height, block) in blocks {
+            assert!(matches!(block.body, Body::OnDisk(_)));
+            assert_eq!(block.decode().unwrap(), chain[height as usize]);
+            assert_eq!(block.raw().unwrap(), serialize(&chain[height as usize]));
+        }
+    }
+
+    #[test]
+    fn test_fork_tree_dump() {
+        let chain = build_chain(20);
+
+        // The end of the chain is stored before its beginning
+        let mut blocks = chain[10..].iter().collect::<Vec<_>>();
+        blocks.extend(chain[..10].iter());
+        let dir = blocks_dir("fork-tree", &[blk_data(&blocks)]);
+
+        // The tree is written when the orphans limit is reached
+        let json_path = dir.join("tree.json");
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            max_orphans: Some(5),
+            fork_tree_path: Some(json_path.clone()),
+            ..regtest_options()
+        });
+        assert_eq!(reader.blocks(&dir).count(), 0);
+        let tree: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(tree["orphans"].as_array().unwrap().len(), 5);
+        assert_eq!(tree["head"], serde_json::Value::Null);
+
+        // The tree is written when the dump flag is set
+        let dot_path = dir.join("tree.dot");
+        let options = BlockReaderOptions {
+            fork_tree_path: Some(dot_path.clone()),
+            ..regtest_options()
+        };
+        options
+            .dump_flag
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let dump_flag = Arc::clone(&options.dump_flag);
+        let mut reader = BlockReader::new(options);
+        assert_eq!(reader.blocks(&dir).take(1).count(), 1);
+        assert!(!dump_flag.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(fs::read_to_string(&dot_path)
+            .unwrap()
+            .contains("[style=dashed]"));
+        assert!(reader.fork_tree().orphans.is_empty());
+
+        // The tree is written while waiting for new blocks
+        let json_path = dir.join("waiting.json");
+        let options = BlockReaderOptions {
+            fork_tree_path: Some(json_path.clone()),
+            ..regtest_options()
+        };
+        let stop_flag = Arc::clone(&options.stop_flag);
+        let dump_flag = Arc::clone(&options.dump_flag);
+        follow_dir(&dir, options, None, usize::MAX, || {
+            dump_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            assert!(wait_for(|| json_path.exists()));
+            stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        let tree: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(tree["blocks"].as_array().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn test_spill_orphans() {
+        let chain = build_chain(30);
+
+        // The end of the chain is stored before its beginning
+        let last = blk_data(&chain[10..].iter().collect::<Vec<_>>());
+        let first = blk_data(&chain[..10].iter().collect::<Vec<_>>());
+        let dir = blocks_dir("spill", &[last, first]);
+
+        let options = || BlockReaderOptions {
+            max_orphans_mem: Some(2048),
+            flush_tip: true,
+            ..regtest_options()
+        };
+
+        // Evicted orphans are lost
+        let mut reader = BlockReader::new(options());
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(heights.len() < 30);
+        assert!(reader.dropped_orphans() > 0);
+
+        // Evicted orphans are read back from the spill file
+        let spill_options = || BlockReaderOptions {
+            spill_dir: Some(dir.to_path_buf()),
+            ..options()
+        };
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            max_blk_files: Some(1),
+            ..spill_options()
+        });
+        assert_eq!(reader.blocks(&dir).count(), 0);
+        assert!(reader.spilled_orphans() > 0);
+        assert!(reader.orphans_mem() <= 2048);
+
+        let mut reader = BlockReader::new(spill_options());
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (0..30).collect::<Vec<_>>());
+        assert_eq!(reader.dropped_orphans(), 0);
+        assert_eq!(reader.spilled_orphans(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_block() {
+        let chain = build_chain(40);
+        let mut data = blk_data(&chain[..20].iter().collect::<Vec<_>>());
+        let incomplete_offset = data.len() as u64;
+        let rest = blk_data(&chain[20..].iter().collect::<Vec<_>>());
+        data.extend(&rest[..50]);
+        let dir = blocks_dir("incomplete", &[data]);
+        let checkpoint_path = dir.join("checkpoint.bin");
+
+        // The read stops before the block being written
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            checkpoint_path: Some(checkpoint_path.clone()),
+            ..regtest_options()
+        });
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (0..20).collect::<Vec<_>>());
+
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        assert_eq!(checkpoint.position.offset, incomplete_offset);
+
+        // In follow mode, the block is read once complete
+        let heights = follow_dir(&dir, regtest_options(), Some(checkpoint), 5, || {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join("blk00000.dat"))
+                .unwrap();
+            file.write_all(&rest[50..]).unwrap();
+        });
+        assert_eq!(heights, (20..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_preallocated_obfuscated() {
+        let key: XorKey = [1, 2, 3, 4, 5, 6, 7, 8];
+        let chain = build_chain(20);
+
+        let mut first = blk_data(&chain[..10].iter().collect::<Vec<_>>());
+        let first_len = first.len() as u64;
+        xor(&mut first, &key, 0);
+        first.extend([0; 4096]);
+        let mut second = blk_data(&chain[10..].iter().collect::<Vec<_>>());
+        xor(&mut second, &key, 0);
+
+        let dir = blocks_dir("preallocated", &[first, second]);
+        fs::write(dir.join("xor.dat"), key).unwrap();
+
+        let skipped = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        reader.set_skip_cb(Box::new(|range| {
+            skipped
+                .lock()
+                .unwrap()
+                .push((range.offset, range.len, range.reason));
+        }));
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        drop(reader);
+
+        assert_eq!(heights, (0..20).collect::<Vec<_>>());
+        assert_eq!(
+            skipped.into_inner().unwrap(),
+            vec![(first_len, 4096, SkipReason::Preallocated)]
+        );
+    }
+
+    #[test]
+    fn test_recover() {
+        let chain = build_chain(12);
+
+        // Garbage between two blocks, then a record cut by a crash at the end of the file
+        let mut first = blk_data(&chain[..5].iter().collect::<Vec<_>>());
+        let garbage_offset = first.len() as u64;
+        first.extend([0xab; 37]);
+        first.extend(blk_data(&chain[5..9].iter().collect::<Vec<_>>()));
+        let truncated_offset = first.len() as u64;
+        let mut truncated = blk_data(&[&chain[9]]);
+        truncated.truncate(truncated.len() - 10);
+        let truncated_len = truncated.len() as u64;
+        first.extend(truncated);
+        let second = blk_data(&chain[9..].iter().collect::<Vec<_>>());
+        let dir = blocks_dir("recover", &[first, second]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            ..regtest_options()
+        });
+        assert!(matches!(
+            reader.read(&dir),
+            Err(ReadError::BadMagic { offset, .. }) if offset == garbage_offset
+        ));
+
+        let skipped = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            recover: true,
+            ..regtest_options()
+        });
+        reader.set_skip_cb(Box::new(|range| {
+            skipped
+                .lock()
+                .unwrap()
+                .push((range.offset, range.len, range.reason));
+        }));
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        drop(reader);
+
+        assert_eq!(heights, (0..12).collect::<Vec<_>>());
+        assert_eq!(
+            skipped.into_inner().unwrap(),
+            vec![
+                (garbage_offset, 37, SkipReason::Corrupt),
+                (truncated_offset, truncated_len, SkipReason::Corrupt),
+            ]
+        );
+
+        // A single file with a record whose body is garbage, followed by other records
+        let chain = build_chain(20);
+        let mut data = blk_data(&chain[..5].iter().collect::<Vec<_>>());
+        let corrupt_offset = data.len() as u64;
+        data.extend(Magic::REGTEST.to_bytes());
+        data.extend(100u32.to_le_bytes());
+        data.extend([0xab; 100]);
+        data.extend(blk_data(&chain[5..].iter().collect::<Vec<_>>()));
+        let dir = blocks_dir("recover-single", &[data]);
+
+        let skipped = std::sync::Mutex::new(vec![]);
+        let mut reader = BlockReader::new(BlockReaderOptions {
+            flush_tip: true,
+            recover: true,
+            ..regtest_options()
+        });
+        reader.set_skip_cb(Box::new(|range| {
+            skipped
+                .lock()
+                .unwrap()
+                .push((range.offset, range.len, range.reason));
+        }));
+        let heights = reader
+            .blocks(&dir)
+            .map(|block| block.unwrap().0)
+            .collect::<Vec<_>>();
+        drop(reader);
+
+        assert_eq!(heights, (0..20).collect::<Vec<_>>());
+        assert_eq!(
+            skipped.into_inner().unwrap(),
+            vec![(corrupt_offset, 108, SkipReason::Corrupt)]
+        );
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let chain = build_chain(3);
+        let mut data = blk_data(&chain.iter().collect::<Vec<_>>());
+        let last_offset = blk_data(&[&chain[0], &chain[1]]).len() as u64;
+
+        // Cut the last block of a file that is not the newest one in the middle
+        data.truncate(data.len() - 10);
+        let dir = blocks_dir("truncated", &[data, blk_data(&[&chain[2]])]);
+
+        let mut reader = BlockReader::new(regtest_options());
+        match reader.read(&dir) {
+            Err(ReadError::Truncated { offset, .. }) => assert_eq!(offset, last_offset),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Blocks of another network
+        let dir = blocks_dir("bad-magic", &[blk_data(&chain.iter().collect::<Vec<_>>())]);
+
+        let mut reader = BlockReader::new(BlockReaderOptions::default());
+        match reader.read(&dir) {
+            Err(ReadError::BadMagic { offset, found, .. }) => {
+                assert_eq!(offset, 0);
+                assert_eq!(found, Magic::REGTEST);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reader_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<BlockReader>();
+    }
+
+    #[test]
+    fn test_signet_magic() {
+        // Challenge of the default signet
+        let challenge = ScriptBuf::from_hex(
+            "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae",
+        )
+        .unwrap();
+
+        assert_eq!(signet_magic(&challenge), Magic::SIGNET);
     }
 }
@@ -1,217 +1,665 @@
-use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fmt::Display;
-use std::rc::Rc;
 
+use log::debug;
+use log::trace;
+
+use crate::fork_tree::ForkTree;
+use crate::fork_tree::TreeBlock;
+
+/// Identifiers of a block and of its parent, used to link the blocks of a `Chain`
 pub trait GetBlockIds<Identifier> {
     fn get_block_id(&self) -> Identifier;
     fn get_block_prev_id(&self) -> Identifier;
+
+    /// Work of the block, used by `ForkChoice::MostWork`
+    fn get_block_work(&self) -> u128 {
+        1
+    }
+
+    /// Time of the block, used by `OrphanEviction::FarthestFromTip`
+    fn get_block_time(&self) -> u32 {
+        0
+    }
+
+    /// Memory held by the block, used by the orphans memory budget
+    fn get_block_mem_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
 }
 
-#[derive(Debug, Clone)]
-enum NextNode<Data> {
-    Single(Rc<RefCell<Node<Data>>>),
-    Multiple(Vec<Rc<RefCell<Node<Data>>>>),
+/// Rule used to select the best branch when the chain forks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForkChoice {
+    /// The branch with the most blocks
+    #[default]
+    Longest,
+    /// The branch with the most cumulative work
+    MostWork,
 }
 
+/// Order in which orphans are evicted once the orphans memory budget is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrphanEviction {
+    /// The orphans inserted first
+    #[default]
+    Oldest,
+    /// The orphans with the latest time, the farthest ahead of the tip
+    FarthestFromTip,
+}
+
+/// Index of a node in the arena of the chain
+type NodeIndex = usize;
+
 #[derive(Debug, Clone)]
-pub struct Node<Data> {
-    block: Option<Data>,
-    prev: Option<Rc<RefCell<Node<Data>>>>,
-    next: Option<NextNode<Data>>,
+struct Node<Data> {
+    block: Data,
+    prev: Option<NodeIndex>,
+    next: Vec<NodeIndex>,
+    // Number of blocks and weight from the first head, compared between branches
+    height: u32,
+    weight: u128,
+    // Next node on the path to the best tip
+    best_next: Option<NodeIndex>,
 }
 
+/// Branch removed from the chain because it lost against the best chain
 #[derive(Debug, Clone)]
-pub struct Chain<I, D> {
-    head: Option<Rc<RefCell<Node<D>>>>,
-    nodes: BTreeMap<I, Rc<RefCell<Node<D>>>>,
-    orphans: BTreeMap<I, D>,
-    genesis_identifier: I,
+pub struct StaleBranch<I, D> {
+    /// Identifier of the last block shared with the best chain
+    pub fork_point: I,
+    /// Blocks of the branch with their distance to the fork point
+    pub blocks: Vec<(u32, D)>,
 }
 
-impl<D> Node<D> {
-    fn add_next(&mut self, node: Rc<RefCell<Node<D>>>) {
-        match &mut self.next {
-            Some(next) => match next {
-                NextNode::Single(next) => {
-                    let nodes = vec![next.clone(), node.clone()];
-                    self.next = Some(NextNode::Multiple(nodes));
-                }
-                NextNode::Multiple(nodes) => nodes.push(node),
-            },
-            None => self.next = Some(NextNode::Single(node)),
-        }
-    }
-
-    fn depth(node: Rc<RefCell<Node<D>>>) -> u32 {
-        match &node.borrow().next {
-            Some(next) => match next {
-                NextNode::Single(next) => 1 + Node::depth(Rc::clone(next)),
-                NextNode::Multiple(nodes) => {
-                    let mut max_depth = 0;
-                    for next in nodes.iter() {
-                        let depth = Node::depth(Rc::clone(next));
-                        if depth > max_depth {
-                            max_depth = depth;
-                        }
-                    }
-                    1 + max_depth
-                }
-            },
-            None => 1,
-        }
-    }
+/// Block waiting for its parent, with its rank in the eviction order
+#[derive(Debug, Clone)]
+struct Orphan<D> {
+    block: D,
+    rank: (u64, u64),
+}
 
-    // Extract all nodes recursively from the current node to the head
-    fn extract_left(node: Rc<RefCell<Node<D>>>) -> Vec<Rc<RefCell<Node<D>>>> {
-        match &node.borrow().prev {
-            Some(prev) => {
-                let mut nodes = Node::extract_left(Rc::clone(prev));
-                nodes.push(Rc::clone(&node));
-                nodes
-            }
-            None => vec![Rc::clone(&node)],
-        }
-    }
+/// Block inserted while a block with the same identifier is already known
+#[derive(Debug, Clone)]
+pub struct Duplicate<D> {
+    /// Copy kept in the chain, None if it already left the chain
+    pub first: Option<D>,
+    pub duplicate: D,
+}
 
-    // Extract all nodes recursively from the current node to the tails
-    fn extract_right(node: Rc<RefCell<Node<D>>>) -> Vec<Rc<RefCell<Node<D>>>> {
-        match &node.borrow().next {
-            Some(next) => match next {
-                NextNode::Single(next) => {
-                    let mut nodes = Node::extract_right(Rc::clone(next));
-                    nodes.push(Rc::clone(&node));
-                    nodes
-                }
-                NextNode::Multiple(nodes) => {
-                    let mut all_nodes = vec![Rc::clone(&node)];
-                    for next in nodes.iter() {
-                        let mut nodes = Node::extract_right(Rc::clone(next));
-                        all_nodes.append(&mut nodes);
-                    }
-                    all_nodes
-                }
-            },
-            None => vec![Rc::clone(&node)],
-        }
-    }
-
-    /// Extract the tail of longest chain from the current node
-    fn longest_right(node: Rc<RefCell<Node<D>>>) -> Rc<RefCell<Node<D>>> {
-        match &node.borrow().next {
-            Some(next) => match next {
-                NextNode::Single(next) => Rc::clone(next),
-                NextNode::Multiple(nodes) => {
-                    let mut max_depth = 0;
-                    let mut longest = Rc::clone(&node);
-                    for next in nodes.iter() {
-                        let depth = Node::depth(Rc::clone(next));
-                        if depth > max_depth {
-                            max_depth = depth;
-                            longest = Rc::clone(next);
-                        }
-                    }
-                    longest
-                }
-            },
-            None => Rc::clone(&node),
-        }
-    }
+/// Buffer of blocks received in any order that resolves forks
+///
+/// Blocks are attached to their parent as soon as it is known, and kept as
+/// orphans until then. The head of the chain is the oldest block, the best
+/// chain goes from the head to the tip selected by the `ForkChoice`, and
+/// `pop_head` removes the head once it is final, pruning the other branches.
+///
+/// ```
+/// use blk_reader::Chain;
+/// use blk_reader::GetBlockIds;
+///
+/// #[derive(Clone)]
+/// struct Header(u32, u32);
+///
+/// impl GetBlockIds<u32> for Header {
+///     fn get_block_id(&self) -> u32 {
+///         self.0
+///     }
+///
+///     fn get_block_prev_id(&self) -> u32 {
+///         self.1
+///     }
+/// }
+///
+/// let mut chain = Chain::new(0);
+/// chain.insert(Header(2, 1));
+/// chain.insert(Header(1, 0));
+/// chain.insert(Header(3, 1));
+/// chain.insert(Header(4, 3));
+///
+/// assert_eq!(chain.best_tip(), Some(4));
+/// assert_eq!(chain.tips(), vec![2, 4]);
+/// assert_eq!(chain.fork_point(&2, &4), Some(1));
+/// assert_eq!(chain.pop_head().unwrap().0, 1);
+/// assert_eq!(chain.best_chain(), vec![3, 4]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Chain<I, D> {
+    // Nodes of the chain, removed nodes leave a free slot reused by the next insertion
+    arena: Vec<Option<Node<D>>>,
+    free: Vec<NodeIndex>,
+    head: Option<NodeIndex>,
+    nodes: BTreeMap<I, NodeIndex>,
+    nodes_mem: usize,
+    // Orphans by parent identifier, blocks of a fork share the same parent
+    orphans: BTreeMap<I, Vec<Orphan<D>>>,
+    genesis_identifier: I,
+    fork_choice: ForkChoice,
+    stale: Vec<StaleBranch<I, D>>,
+    // Last node of the best chain according to the fork choice
+    best_tip: Option<NodeIndex>,
+    // Orphans by rank, the first one is evicted first
+    eviction_queue: BTreeSet<((u64, u64), I)>,
+    eviction: OrphanEviction,
+    orphans_mem: usize,
+    max_orphans_mem: Option<usize>,
+    // Number of orphans inserted, used to rank them
+    orphans_seq: u64,
+    // Changes kept for the block reader until it takes them, only if tracked
+    track_changes: bool,
+    evicted: Vec<D>,
+    attached: Vec<I>,
+    duplicates: Vec<Duplicate<D>>,
 }
 
 impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> Chain<I, D> {
     pub fn new(genesis_identifier: I) -> Chain<I, D> {
         Chain {
+            arena: vec![],
+            free: vec![],
             head: None,
             orphans: BTreeMap::new(),
             nodes: BTreeMap::new(),
+            nodes_mem: 0,
             genesis_identifier,
+            fork_choice: ForkChoice::default(),
+            stale: vec![],
+            best_tip: None,
+            eviction_queue: BTreeSet::new(),
+            eviction: OrphanEviction::default(),
+            orphans_mem: 0,
+            max_orphans_mem: None,
+            orphans_seq: 0,
+            track_changes: false,
+            evicted: vec![],
+            attached: vec![],
+            duplicates: vec![],
         }
     }
 
+    pub fn set_fork_choice(&mut self, fork_choice: ForkChoice) {
+        self.fork_choice = fork_choice;
+    }
+
+    /// Drop orphans once they hold more than `max_orphans_mem` bytes
+    pub fn set_max_orphans_mem(
+        &mut self,
+        max_orphans_mem: Option<usize>,
+        eviction: OrphanEviction,
+    ) {
+        self.max_orphans_mem = max_orphans_mem;
+        self.eviction = eviction;
+    }
+
+    /// Keep the evicted, attached, duplicate and stale blocks until they are taken
+    pub(crate) fn set_track_changes(&mut self, track_changes: bool) {
+        self.track_changes = track_changes;
+    }
+
     pub fn next_id(&self) -> I {
-        match &self.head {
-            Some(head) => head.borrow().block.as_ref().unwrap().get_block_id(),
+        match self.head {
+            Some(head) => self.node(head).block.get_block_id(),
             None => self.genesis_identifier,
         }
     }
 
-    fn longest_chain(&self) -> Option<Rc<RefCell<Node<D>>>> {
-        match &self.head {
-            Some(head) => Some(Node::longest_right(Rc::clone(head))),
-            None => None,
+    fn node(&self, index: NodeIndex) -> &Node<D> {
+        self.arena[index].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, index: NodeIndex) -> &mut Node<D> {
+        self.arena[index].as_mut().unwrap()
+    }
+
+    /// Store a node in the arena and return its index
+    fn alloc(&mut self, node: Node<D>) -> NodeIndex {
+        self.nodes_mem += node.block.get_block_mem_size();
+        match self.free.pop() {
+            Some(index) => {
+                self.arena[index] = Some(node);
+                index
+            }
+            None => {
+                self.arena.push(Some(node));
+                self.arena.len() - 1
+            }
         }
     }
 
-    pub fn longest_chain_depth(&self) -> u32 {
-        match &self.head {
-            Some(head) => Node::depth(head.clone()),
-            None => 0,
+    /// Remove a node from the arena and return it
+    fn release(&mut self, index: NodeIndex) -> Node<D> {
+        let node = self.arena[index].take().unwrap();
+        self.nodes_mem -= node.block.get_block_mem_size();
+        self.free.push(index);
+        node
+    }
+
+    // Extract all nodes from the given node to the tails with their distance to it
+    fn extract_branch(&self, index: NodeIndex) -> Vec<(u32, NodeIndex)> {
+        let mut nodes = vec![];
+        let mut stack = vec![(0, index)];
+        while let Some((distance, index)) = stack.pop() {
+            for next in self.node(index).next.iter().rev() {
+                stack.push((distance + 1, *next));
+            }
+            nodes.push((distance, index));
         }
+        nodes
     }
 
-    fn tails(&self) -> Vec<Rc<RefCell<Node<D>>>> {
-        match &self.head {
-            Some(head) => Node::extract_right(Rc::clone(head))
-                .iter()
-                .filter(|node| node.borrow().next.is_none())
-                .map(|node| Rc::clone(node))
+    // Extract all nodes from the head to the given node
+    fn extract_left(&self, index: NodeIndex) -> Vec<NodeIndex> {
+        let mut nodes = vec![index];
+        let mut index = index;
+        while let Some(prev) = self.node(index).prev {
+            nodes.push(prev);
+            index = prev;
+        }
+        nodes.reverse();
+        nodes
+    }
+
+    /// Return the number of blocks of the best chain according to the fork choice
+    pub fn best_chain_depth(&self) -> u32 {
+        match (self.head, self.best_tip) {
+            (Some(head), Some(best_tip)) => self.node(best_tip).height - self.node(head).height + 1,
+            _ => 0,
+        }
+    }
+
+    /// Return the identifiers of the best chain, from the head to the tip
+    pub fn best_chain(&self) -> Vec<I> {
+        let mut ids = vec![];
+        let mut index = self.head;
+        while let Some(current) = index {
+            let node = self.node(current);
+            ids.push(node.block.get_block_id());
+            index = node.best_next;
+        }
+
+        ids
+    }
+
+    /// Call `f` on every block of the chain, parents first, then on the orphans
+    pub fn for_each(&self, mut f: impl FnMut(&D)) {
+        if let Some(head) = self.head {
+            for (_, index) in self.extract_branch(head) {
+                f(&self.node(index).block);
+            }
+        }
+
+        for orphan in self.orphans.values().flatten() {
+            f(&orphan.block);
+        }
+    }
+
+    /// Return true if the block is attached to the chain
+    pub fn contains(&self, id: &I) -> bool {
+        self.nodes.contains_key(id)
+    }
+
+    /// Return a block attached to the chain
+    pub fn get(&self, id: &I) -> Option<&D> {
+        Some(&self.node(*self.nodes.get(id)?).block)
+    }
+
+    /// Return the height of a block attached to the chain, the head is at height 0
+    pub fn height_of(&self, id: &I) -> Option<u32> {
+        let head = self.node(self.head?);
+        Some(self.node(*self.nodes.get(id)?).height - head.height)
+    }
+
+    /// Return the identifier of the last block of the best chain
+    pub fn best_tip(&self) -> Option<I> {
+        Some(self.node(self.best_tip?).block.get_block_id())
+    }
+
+    /// Return the identifiers of the last block of every branch
+    pub fn tips(&self) -> Vec<I> {
+        self.tails()
+            .into_iter()
+            .map(|index| self.node(index).block.get_block_id())
+            .collect()
+    }
+
+    /// Return the identifiers of the ancestors of a block attached to the chain,
+    /// from its parent to the head
+    pub fn ancestors(&self, id: &I) -> Vec<I> {
+        let index = match self.nodes.get(id) {
+            Some(index) => *index,
+            None => return vec![],
+        };
+
+        let mut ids = self
+            .extract_left(index)
+            .into_iter()
+            .map(|index| self.node(index).block.get_block_id())
+            .collect::<Vec<_>>();
+        ids.pop();
+        ids.reverse();
+        ids
+    }
+
+    /// Return the last block shared by the branches of two blocks attached to the chain
+    pub fn fork_point(&self, a: &I, b: &I) -> Option<I> {
+        let mut a = *self.nodes.get(a)?;
+        let mut b = *self.nodes.get(b)?;
+
+        while a != b {
+            if self.node(a).height >= self.node(b).height {
+                a = self.node(a).prev?;
+            } else {
+                b = self.node(b).prev?;
+            }
+        }
+
+        Some(self.node(a).block.get_block_id())
+    }
+
+    /// Return a snapshot of the blocks and orphans of the chain, to export them
+    pub fn fork_tree(&self) -> ForkTree {
+        let mut best = BTreeSet::new();
+        let mut index = self.head;
+        while let Some(current) = index {
+            best.insert(current);
+            index = self.node(current).best_next;
+        }
+
+        let head_height = self.head.map_or(0, |head| self.node(head).height);
+        let blocks = match self.head {
+            Some(head) => self
+                .extract_branch(head)
+                .into_iter()
+                .map(|(_, index)| {
+                    let node = self.node(index);
+                    TreeBlock {
+                        id: node.block.get_block_id().to_string(),
+                        parent: node.block.get_block_prev_id().to_string(),
+                        depth: Some(node.height - head_height),
+                        best: best.contains(&index),
+                    }
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        let orphans = self
+            .orphans
+            .values()
+            .flatten()
+            .map(|orphan| TreeBlock {
+                id: orphan.block.get_block_id().to_string(),
+                parent: orphan.block.get_block_prev_id().to_string(),
+                depth: None,
+                best: false,
+            })
+            .collect();
+
+        ForkTree {
+            head: self
+                .head
+                .map(|head| self.node(head).block.get_block_id().to_string()),
+            best_tip: self.best_tip().map(|id| id.to_string()),
+            blocks,
+            orphans,
+        }
+    }
+
+    /// Iterate over the branches of the chain, each from the head to one of the tips
+    pub fn branches(&self) -> impl Iterator<Item = Vec<I>> + '_ {
+        self.tails().into_iter().map(|tail| {
+            self.extract_left(tail)
+                .into_iter()
+                .map(|index| self.node(index).block.get_block_id())
+                .collect()
+        })
+    }
+
+    fn tails(&self) -> Vec<NodeIndex> {
+        match self.head {
+            Some(head) => self
+                .extract_branch(head)
+                .into_iter()
+                .map(|(_, index)| index)
+                .filter(|index| self.node(*index).next.is_empty())
                 .collect(),
             None => vec![],
         }
     }
 
     pub fn orphans(&self) -> usize {
-        self.orphans.len()
+        self.eviction_queue.len()
+    }
+
+    /// Return the memory held by the orphans
+    pub fn orphans_mem(&self) -> usize {
+        self.orphans_mem
+    }
+
+    /// Return the memory held by the blocks of the chain, orphans included
+    pub fn mem(&self) -> usize {
+        self.nodes_mem + self.orphans_mem
+    }
+
+    fn add_orphan(&mut self, block: D) {
+        let prev_id = block.get_block_prev_id();
+
+        self.orphans_seq += 1;
+        let rank = match self.eviction {
+            OrphanEviction::Oldest => (0, self.orphans_seq),
+            OrphanEviction::FarthestFromTip => (
+                u32::MAX as u64 - block.get_block_time() as u64,
+                self.orphans_seq,
+            ),
+        };
+
+        self.orphans_mem += block.get_block_mem_size();
+        self.eviction_queue.insert((rank, prev_id));
+        self.orphans
+            .entry(prev_id)
+            .or_default()
+            .push(Orphan { block, rank });
+
+        // Evict orphans until they fit in the budget
+        let max_orphans_mem = match self.max_orphans_mem {
+            Some(max_orphans_mem) => max_orphans_mem,
+            None => return,
+        };
+        while self.orphans_mem > max_orphans_mem {
+            let (rank, prev_id) = match self.eviction_queue.pop_first() {
+                Some(first) => first,
+                None => return,
+            };
+
+            let siblings = self.orphans.get_mut(&prev_id).unwrap();
+            let index = siblings
+                .iter()
+                .position(|orphan| orphan.rank == rank)
+                .unwrap();
+            let orphan = siblings.remove(index);
+            if siblings.is_empty() {
+                self.orphans.remove(&prev_id);
+            }
+
+            self.orphans_mem -= orphan.block.get_block_mem_size();
+            if self.track_changes {
+                self.evicted.push(orphan.block);
+            }
+        }
+    }
+
+    /// Remove all the orphans waiting for the given parent
+    fn remove_orphans(&mut self, prev_id: &I) -> Vec<D> {
+        let orphans = self.orphans.remove(prev_id).unwrap_or_default();
+        orphans
+            .into_iter()
+            .map(|orphan| {
+                self.eviction_queue.remove(&(orphan.rank, *prev_id));
+                self.orphans_mem -= orphan.block.get_block_mem_size();
+                orphan.block
+            })
+            .collect()
+    }
+
+    /// Return the orphans evicted to stay in the memory budget since the last call
+    pub(crate) fn take_evicted(&mut self) -> Vec<D> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Return the blocks rejected because they were already inserted since the last call
+    pub(crate) fn take_duplicates(&mut self) -> Vec<Duplicate<D>> {
+        std::mem::take(&mut self.duplicates)
+    }
+
+    /// Return the copy of the block already known with the same identifier
+    /// The outer option is None if the block is unknown, the inner one is None
+    /// if the block already left the chain
+    fn find_duplicate(&self, block: &D) -> Option<Option<D>> {
+        let block_hash = block.get_block_id();
+
+        if block_hash == self.genesis_identifier {
+            return Some(None);
+        }
+
+        if let Some(index) = self.nodes.get(&block_hash) {
+            return Some(Some(self.node(*index).block.clone()));
+        }
+
+        self.orphans
+            .get(&block.get_block_prev_id())?
+            .iter()
+            .find(|orphan| orphan.block.get_block_id() == block_hash)
+            .map(|orphan| Some(orphan.block.clone()))
+    }
+
+    /// Return the identifiers of the blocks attached to the chain since the last call
+    pub(crate) fn take_attached(&mut self) -> Vec<I> {
+        std::mem::take(&mut self.attached)
     }
 
     pub fn insert(&mut self, block: D) {
+        // Orphans are inserted once their parent is attached, siblings in insertion order
+        let mut blocks = vec![block];
+        while let Some(block) = blocks.pop() {
+            if let Some(block_hash) = self.attach(block) {
+                blocks.extend(self.remove_orphans(&block_hash).into_iter().rev());
+            }
+        }
+    }
+
+    /// Attach a block to its parent, or keep it as an orphan
+    /// Return the identifier of the block if it was attached
+    fn attach(&mut self, block: D) -> Option<I> {
         let block_hash = block.get_block_id();
         let prev_hash = block.get_block_prev_id();
 
+        // The block is stored more than once, keep the first copy
+        if let Some(first) = self.find_duplicate(&block) {
+            if self.track_changes {
+                self.duplicates.push(Duplicate {
+                    first,
+                    duplicate: block,
+                });
+            }
+            return None;
+        }
+
+        let own_weight = match self.fork_choice {
+            ForkChoice::Longest => 1,
+            ForkChoice::MostWork => block.get_block_work(),
+        };
+
         // This is the genesis block
-        if self.head.is_none() && prev_hash == self.genesis_identifier {
-            let node = Rc::new(RefCell::new(Node {
-                block: Some(block),
+        let index = if self.head.is_none() && prev_hash == self.genesis_identifier {
+            let index = self.alloc(Node {
+                block,
                 prev: None,
-                next: None,
-            }));
+                next: vec![],
+                height: 0,
+                weight: own_weight,
+                best_next: None,
+            });
 
-            self.nodes.insert(block_hash, node.clone());
-            self.head = Some(node);
+            self.head = Some(index);
 
-            return;
-        }
+            index
+        } else {
+            match self.nodes.get(&prev_hash) {
+                // If the new block is an orphan, add it to the orphans list and return
+                None => {
+                    self.add_orphan(block);
+                    return None;
+                }
+                // If the new block is a child of a parent node, add it to the parent's next list
+                Some(&parent_index) => {
+                    let parent = self.node(parent_index);
+                    let (height, weight) = (parent.height + 1, parent.weight + own_weight);
 
-        match self.nodes.get_mut(&prev_hash) {
-            // If the new block is an orphan, add it to the orphans list and return
-            None => {
-                self.orphans.insert(prev_hash, block);
-                return;
+                    let index = self.alloc(Node {
+                        block,
+                        prev: Some(parent_index),
+                        next: vec![],
+                        height,
+                        weight,
+                        best_next: None,
+                    });
+
+                    // Add the new node to the parent's next list
+                    self.node_mut(parent_index).next.push(index);
+
+                    index
+                }
             }
-            // If the new block is a child of a parent node, add it to the parent's next list
-            Some(parent_node) => {
-                let node = Rc::new(RefCell::new(Node {
-                    block: Some(block),
-                    prev: Some(parent_node.clone()),
-                    next: None,
-                }));
+        };
 
-                // Add the new node to the parent's next list
-                parent_node.borrow_mut().add_next(node.clone());
+        // Add the new node to the nodes map
+        self.nodes.insert(block_hash, index);
+        if self.track_changes {
+            self.attached.push(block_hash);
+        }
 
-                // Add the new node to the nodes map
-                self.nodes.insert(block_hash, node.clone());
+        self.update_best_tip(index);
+
+        Some(block_hash)
+    }
 
-                node
+    /// Make the node the best tip if its branch is heavier than the current best chain
+    /// Only the nodes between the new tip and the fork point with the previous best
+    /// chain are updated
+    fn update_best_tip(&mut self, index: NodeIndex) {
+        let mut old = match self.best_tip {
+            Some(best_tip) if self.node(index).weight <= self.node(best_tip).weight => return,
+            Some(best_tip) => best_tip,
+            None => {
+                self.best_tip = Some(index);
+                return;
             }
         };
 
-        // We inserted a new block, check if we can insert any orphans
-        match self.orphans.remove(&block_hash) {
-            Some(orphan) => self.insert(orphan),
-            None => {}
-        };
+        let mut new = index;
+        while new != old {
+            if self.node(new).height >= self.node(old).height {
+                let prev = match self.node(new).prev {
+                    Some(prev) => prev,
+                    None => break,
+                };
+                self.node_mut(prev).best_next = Some(new);
+                new = prev;
+            } else {
+                old = match self.node(old).prev {
+                    Some(prev) => prev,
+                    None => break,
+                };
+            }
+        }
+
+        self.best_tip = Some(index);
+    }
+
+    /// Return the branches removed by `pop_head` since the last call
+    pub(crate) fn take_stale(&mut self) -> Vec<StaleBranch<I, D>> {
+        std::mem::take(&mut self.stale)
     }
 
     /// Pop head: remove the head of the chain and return it
@@ -220,91 +668,63 @@ impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> Chain<I, D>
     /// If the head has a single next node, set the head to the next node
     /// If the head has multiple next nodes, remove all nodes except the next node from the longest chain
     pub fn pop_head(&mut self) -> Option<D> {
-        let longest_chain = self.longest_chain()?;
-
-        let tail = longest_chain.clone();
-        let chain = Node::extract_left(tail);
-
-        let mut head_node = chain.first()?.borrow_mut();
-        let head = head_node.block.take().unwrap();
+        let head_index = self.head.take()?;
+        let head_node = self.release(head_index);
+        let head = head_node.block;
         let head_id = head.get_block_id();
 
-        let next = chain.get(1).map(|node| (*node).clone());
-
-        // Remove the head from the nodes map
+        // Remove the head from the nodes map, the chain now builds on it
         self.nodes.remove(&head_id);
+        self.genesis_identifier = head_id;
 
-        let next = match next {
-            Some(next) => next,
-            None => {
-                self.head = None;
-                return Some(head);
-            }
-        };
+        let next = head_node.best_next;
+        match next {
+            Some(next) => self.node_mut(next).prev = None,
+            // The chain is empty, the next block will build on the popped one
+            None => self.best_tip = None,
+        }
 
-        // Update the prev node for the next head
-        next.borrow_mut().prev = None;
+        // Remove the branches that are not part of the best chain
+        for index in head_node.next {
+            if Some(index) == next {
+                continue;
+            }
 
-        // Update the new head
-        match head_node.next.as_ref() {
-            None => {
-                self.head = None;
-                return Some(head);
+            let nodes = self.extract_branch(index);
+            debug!(fork_point:% = head_id, blocks = nodes.len(); "Removing stale branch");
+            let mut blocks = vec![];
+            for (distance, index) in nodes {
+                let block = self.release(index).block;
+                let node_id = block.get_block_id();
+                trace!(hash:% = node_id; "Removing stale block");
+                self.nodes.remove(&node_id);
+                blocks.push((distance + 1, block));
             }
-            Some(next_nodes) => match next_nodes {
-                NextNode::Single(node) => {
-                    self.head = Some(node.clone());
-                    return Some(head);
-                }
-                NextNode::Multiple(nodes) => {
-                    for node in nodes.iter() {
-                        // Continue if node is next
-                        if Rc::ptr_eq(&next, node) {
-                            println!(
-                                "Continue, ignoring {}",
-                                node.borrow().block.as_ref().unwrap().get_block_id()
-                            );
-                            continue;
-                        }
-
-                        let nodes = Node::extract_right(Rc::clone(node));
-                        println!("Removing nodes: {}", nodes.len());
-                        for node in nodes.iter() {
-                            let node_id = node.borrow().block.as_ref().unwrap().get_block_id();
-                            println!("Removing node {}", node_id);
-                            self.nodes.remove(&node_id);
-                        }
-                    }
 
-                    self.head = Some(next);
-                    return Some(head);
-                }
-            },
+            if self.track_changes {
+                self.stale.push(StaleBranch {
+                    fork_point: head_id,
+                    blocks,
+                });
+            }
         }
+
+        self.head = next;
+        Some(head)
     }
 }
 
-impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> std::fmt::Display for Chain<I, D> {
+impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> std::fmt::Display
+    for Chain<I, D>
+{
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let tails = self.tails();
-
-        println!("nTails: {}", tails.len());
-
-        for tail in tails {
-            let nodes = Node::extract_left(tail.clone());
-
+        for branch in self.branches() {
             writeln!(
                 f,
                 "{}",
-                nodes
+                branch
                     .iter()
-                    .map(|node| node
-                        .borrow()
-                        .block
-                        .as_ref()
-                        .unwrap()
-                        .get_block_id()
-                        .to_string())
+                    .map(|id| id.to_string())
                     .collect::<Vec<String>>()
                     .join(" -> ")
             )?;
@@ -315,7 +735,8 @@ impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> std::fmt::D
         }
 
         write!(f, "Orphans: ")?;
-        for (_, data) in self.orphans.iter() {
+        for orphan in self.orphans.values().flatten() {
+            let data = &orphan.block;
             write!(
                 f,
                 "{} (prev: {}),",
@@ -323,7 +744,7 @@ impl<I: PartialEq + Ord + Copy + Display, D: Clone + GetBlockIds<I>> std::fmt::D
                 data.get_block_prev_id()
             )?;
         }
-        write!(f, "\n")?;
+        writeln!(f)?;
 
         Ok(())
     }
@@ -337,6 +758,7 @@ mod tests {
     struct Block {
         block_id: &'static str,
         prev_block_id: &'static str,
+        work: u128,
     }
 
     impl Block {
@@ -344,6 +766,15 @@ mod tests {
             Block {
                 block_id,
                 prev_block_id,
+                work: 1,
+            }
+        }
+
+        fn with_work(block_id: &'static str, prev_block_id: &'static str, work: u128) -> Block {
+            Block {
+                block_id,
+                prev_block_id,
+                work,
             }
         }
     }
@@ -356,6 +787,10 @@ mod tests {
         fn get_block_prev_id(&self) -> &'static str {
             self.prev_block_id
         }
+
+        fn get_block_work(&self) -> u128 {
+            self.work
+        }
     }
 
     #[test]
@@ -377,56 +812,301 @@ mod tests {
         chain.insert(block5);
 
         assert_eq!(chain.orphans.len(), 0);
-        assert_eq!(chain.longest_chain_depth(), 5);
-
-        println!("Chains: \n{}", chain);
+        assert_eq!(chain.best_chain_depth(), 5);
+        assert_eq!(
+            chain.branches().collect::<Vec<_>>(),
+            vec![vec!["1", "2", "3a", "4", "5"], vec!["1", "2", "3b"]]
+        );
 
         let block = chain.pop_head();
-        println!(
-            "Pop head {}, new head {}",
-            block.as_ref().unwrap().block_id,
-            chain
-                .head
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .block
-                .as_ref()
-                .unwrap()
-                .block_id
-        );
         assert_eq!(block.unwrap().block_id, "1");
-        assert_eq!(chain.longest_chain_depth(), 4);
-        println!("Chains: \n{}", chain);
+        assert_eq!(chain.next_id(), "2");
+        assert_eq!(chain.best_chain_depth(), 4);
+        assert_eq!(chain.tips(), vec!["5", "3b"]);
 
         let block = chain.pop_head();
-        println!(
-            "Pop head {}, new head {}",
-            block.as_ref().unwrap().block_id,
-            chain
-                .head
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .block
-                .as_ref()
-                .unwrap()
-                .block_id
-        );
         assert_eq!(block.unwrap().block_id, "2");
-        assert_eq!(chain.longest_chain_depth(), 3);
-        println!("Chains: \n{}", chain);
+        assert_eq!(chain.next_id(), "3a");
+        assert_eq!(chain.best_chain_depth(), 3);
+        assert_eq!(chain.tips(), vec!["5"]);
+        assert!(!chain.contains(&"3b"));
 
         // Insert orphan
         chain.insert(Block::new("7", "6"));
         assert_eq!(chain.orphans.len(), 1);
-        assert_eq!(chain.longest_chain_depth(), 3);
-        println!("Chains: \n{}", chain);
+        assert_eq!(chain.best_chain_depth(), 3);
+        assert!(!chain.contains(&"7"));
 
         // Insert orphan parent
         chain.insert(Block::new("6", "5"));
         assert_eq!(chain.orphans.len(), 0);
-        assert_eq!(chain.longest_chain_depth(), 5);
-        println!("Chains: \n{}", chain);
+        assert_eq!(chain.best_chain_depth(), 5);
+        assert_eq!(chain.best_tip(), Some("7"));
+    }
+
+    #[test]
+    fn test_fork_tree_queries() {
+        let mut chain = Chain::new("genesis-identifier");
+        for block in [
+            Block::new("1", "genesis-identifier"),
+            Block::new("2", "1"),
+            Block::new("3a", "2"),
+            Block::new("4a", "3a"),
+            Block::new("3b", "2"),
+            Block::new("4b", "3b"),
+            Block::new("5b", "4b"),
+        ] {
+            chain.insert(block);
+        }
+
+        assert_eq!(chain.get(&"4a").unwrap().prev_block_id, "3a");
+        assert!(chain.get(&"6").is_none());
+        assert_eq!(chain.height_of(&"1"), Some(0));
+        assert_eq!(chain.height_of(&"5b"), Some(4));
+        assert_eq!(chain.ancestors(&"4a"), vec!["3a", "2", "1"]);
+        assert!(chain.ancestors(&"6").is_empty());
+        assert_eq!(chain.fork_point(&"4a", &"5b"), Some("2"));
+        assert_eq!(chain.fork_point(&"2", &"4a"), Some("2"));
+        assert_eq!(chain.best_tip(), Some("5b"));
+        assert_eq!(
+            chain.to_string(),
+            "1 -> 2 -> 3a -> 4a\n1 -> 2 -> 3b -> 4b -> 5b\n"
+        );
+    }
+
+    #[test]
+    fn test_chain_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Chain<&'static str, Block>>();
+    }
+
+    #[test]
+    fn test_fork_choice() {
+        let blocks = [
+            Block::new("1", "genesis-identifier"),
+            Block::new("2a", "1"),
+            Block::new("3a", "2a"),
+            Block::new("4a", "3a"),
+            Block::with_work("2b", "1", 10),
+        ];
+
+        // The longest branch wins by default
+        let mut chain = Chain::new("genesis-identifier");
+        for block in blocks.iter() {
+            chain.insert(block.clone());
+        }
+        assert_eq!(chain.best_chain_depth(), 4);
+        assert_eq!(chain.pop_head().unwrap().block_id, "1");
+        assert_eq!(chain.pop_head().unwrap().block_id, "2a");
+
+        // The shorter branch has more work
+        let mut chain = Chain::new("genesis-identifier");
+        chain.set_fork_choice(ForkChoice::MostWork);
+        for block in blocks.iter() {
+            chain.insert(block.clone());
+        }
+        assert_eq!(chain.best_chain_depth(), 2);
+        assert_eq!(chain.pop_head().unwrap().block_id, "1");
+        assert_eq!(chain.pop_head().unwrap().block_id, "2b");
+        assert_eq!(chain.best_chain_depth(), 0);
+    }
+
+    #[test]
+    fn test_best_tip_switch() {
+        let mut chain = Chain::new("genesis-identifier");
+
+        chain.insert(Block::new("1", "genesis-identifier"));
+        chain.insert(Block::new("2a", "1"));
+        chain.insert(Block::new("3a", "2a"));
+        chain.insert(Block::new("2b", "1"));
+        chain.insert(Block::new("3b", "2b"));
+        assert_eq!(chain.best_chain(), vec!["1", "2a", "3a"]);
+
+        // The second branch takes the lead, then the first one again
+        chain.insert(Block::new("4b", "3b"));
+        assert_eq!(chain.best_chain(), vec!["1", "2b", "3b", "4b"]);
+        chain.insert(Block::new("4a", "3a"));
+        chain.insert(Block::new("5a", "4a"));
+        assert_eq!(chain.best_chain(), vec!["1", "2a", "3a", "4a", "5a"]);
+        assert_eq!(chain.best_chain_depth(), 5);
+    }
+
+    #[test]
+    fn test_long_orphan_chain() {
+        let ids = (0..100_000)
+            .map(|i| &*Box::leak(i.to_string().into_boxed_str()))
+            .collect::<Vec<&'static str>>();
+
+        // Every block is an orphan until the first one is inserted
+        let mut chain = Chain::new("genesis-identifier");
+        for i in (1..ids.len()).rev() {
+            chain.insert(Block::new(ids[i], ids[i - 1]));
+        }
+        chain.insert(Block::new(ids[0], "genesis-identifier"));
+
+        assert_eq!(chain.orphans(), 0);
+        assert_eq!(chain.best_chain_depth(), ids.len() as u32);
+        assert_eq!(chain.pop_head().unwrap().block_id, ids[0]);
+        assert_eq!(chain.best_chain_depth(), ids.len() as u32 - 1);
+    }
+
+    #[test]
+    fn test_fork_tree() {
+        let mut chain = Chain::new("genesis-identifier");
+        for block in [
+            Block::new("1", "genesis-identifier"),
+            Block::new("2a", "1"),
+            Block::new("2b", "1"),
+            Block::new("3b", "2b"),
+            Block::new("5", "4"),
+        ] {
+            chain.insert(block);
+        }
+
+        let tree = chain.fork_tree();
+        assert_eq!(tree.head.as_deref(), Some("1"));
+        assert_eq!(tree.best_tip.as_deref(), Some("3b"));
+        let blocks = tree
+            .blocks
+            .iter()
+            .map(|block| (block.id.as_str(), block.depth, block.best))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blocks,
+            vec![
+                ("1", Some(0), true),
+                ("2a", Some(1), false),
+                ("2b", Some(1), true),
+                ("3b", Some(2), true)
+            ]
+        );
+        assert_eq!(tree.orphans.len(), 1);
+        assert_eq!(tree.orphans[0].parent, "4");
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph chain {"));
+        assert!(dot.contains("\"2b\" -> \"3b\";"));
+        assert!(dot.contains("\"4\" -> \"5\" [style=dashed];"));
+        assert!(!dot.contains("\"genesis-identifier\" -> \"1\""));
+
+        let json = tree.to_json();
+        assert!(json.contains("\"best_tip\": \"3b\""));
+        assert!(json.contains("\"depth\": null"));
+    }
+
+    #[test]
+    fn test_out_of_order_forks() {
+        let mut chain = Chain::new("genesis-identifier");
+        chain.set_track_changes(true);
+
+        // Both sides of two forks arrive before their parent
+        chain.insert(Block::new("2b", "1"));
+        chain.insert(Block::new("3a", "2a"));
+        chain.insert(Block::new("3b", "2a"));
+        chain.insert(Block::new("4", "3a"));
+        chain.insert(Block::new("2a", "1"));
+        assert_eq!(chain.orphans(), 5);
+
+        chain.insert(Block::new("1", "genesis-identifier"));
+        assert_eq!(chain.orphans(), 0);
+        assert_eq!(chain.best_chain(), vec!["1", "2a", "3a", "4"]);
+
+        assert_eq!(chain.pop_head().unwrap().block_id, "1");
+        let stale = chain.take_stale();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].blocks[0].1.block_id, "2b");
+
+        assert_eq!(chain.pop_head().unwrap().block_id, "2a");
+        let stale = chain.take_stale();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].blocks[0].1.block_id, "3b");
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut chain = Chain::new("genesis-identifier");
+        chain.set_track_changes(true);
+
+        chain.insert(Block::new("1", "genesis-identifier"));
+        chain.insert(Block::new("3", "2"));
+        chain.insert(Block::new("2", "1"));
+        chain.insert(Block::new("3", "2"));
+        chain.insert(Block::new("5", "4"));
+        chain.insert(Block::new("5", "4"));
+        assert_eq!(chain.pop_head().unwrap().block_id, "1");
+        chain.insert(Block::new("1", "genesis-identifier"));
+
+        // A single copy of each block is kept
+        assert_eq!(chain.best_chain(), vec!["2", "3"]);
+        assert_eq!(chain.orphans(), 1);
+
+        let duplicates = chain.take_duplicates();
+        let ids = duplicates
+            .iter()
+            .map(|duplicate| {
+                (
+                    duplicate.duplicate.block_id,
+                    duplicate.first.as_ref().map(|first| first.block_id),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![("3", Some("3")), ("5", Some("5")), ("1", None)]);
+    }
+
+    #[test]
+    fn test_untracked_changes() {
+        let mut chain = Chain::new("genesis-identifier");
+        chain.set_max_orphans_mem(Some(std::mem::size_of::<Block>()), OrphanEviction::Oldest);
+
+        chain.insert(Block::new("1", "genesis-identifier"));
+        chain.insert(Block::new("2a", "1"));
+        chain.insert(Block::new("2b", "1"));
+        chain.insert(Block::new("2b", "1"));
+        chain.insert(Block::new("4", "3"));
+        chain.insert(Block::new("5", "4"));
+        chain.insert(Block::new("3a", "2a"));
+        assert_eq!(chain.pop_head().unwrap().block_id, "1");
+        assert_eq!(chain.best_chain(), vec!["2a", "3a"]);
+        assert_eq!(chain.orphans(), 1);
+
+        // Nothing is kept for a caller that doesn't take the changes
+        assert!(chain.attached.is_empty());
+        assert!(chain.duplicates.is_empty());
+        assert!(chain.stale.is_empty());
+        assert!(chain.evicted.is_empty());
+    }
+
+    #[test]
+    fn test_orphans_eviction() {
+        let block_size = std::mem::size_of::<Block>();
+
+        let mut chain = Chain::new("genesis-identifier");
+        chain.set_track_changes(true);
+        chain.set_max_orphans_mem(Some(3 * block_size), OrphanEviction::Oldest);
+
+        chain.insert(Block::new("1", "genesis-identifier"));
+        chain.insert(Block::new("3", "2"));
+        chain.insert(Block::new("4", "3"));
+        chain.insert(Block::new("6", "5"));
+        assert_eq!(chain.orphans_mem(), 3 * block_size);
+        assert!(chain.take_evicted().is_empty());
+
+        // The oldest orphan leaves the pool
+        chain.insert(Block::new("7", "6"));
+        assert_eq!(chain.orphans(), 3);
+        let evicted = chain.take_evicted();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].block_id, "3");
+
+        // Attached orphans no longer count
+        chain.insert(Block::new("2", "1"));
+        chain.insert(Block::new("3", "2"));
+        assert_eq!(chain.orphans(), 2);
+        assert_eq!(chain.orphans_mem(), 2 * block_size);
+        assert_eq!(chain.mem(), 6 * block_size);
+        assert_eq!(chain.take_attached(), vec!["1", "2", "3", "4"]);
+
+        chain.pop_head();
+        assert_eq!(chain.mem(), 5 * block_size);
     }
 }
@@ -0,0 +1,62 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::ReadError;
+
+/// Position of a block record in the blk files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLocation {
+    pub blk_index: u32,
+    pub offset: u64,
+}
+
+/// State of a `BlockReader`, used to resume reading where it stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Height of the next block to deliver
+    pub height: u32,
+    /// Hash of the last delivered block
+    pub best_hash: [u8; 32],
+    /// Position of the next record to read
+    pub position: BlockLocation,
+    /// Blocks read but not delivered yet, parents first
+    pub pending: Vec<BlockLocation>,
+    /// Hash of the start block if it was not delivered yet
+    pub start_hash: Option<[u8; 32]>,
+}
+
+impl Checkpoint {
+    /// Load a checkpoint saved with `Checkpoint::save`
+    pub fn load(path: &Path) -> Result<Checkpoint, ReadError> {
+        let file = File::open(path).map_err(|err| ReadError::io(path.display(), err))?;
+
+        bincode::deserialize_from(BufReader::new(file)).map_err(|source| ReadError::Checkpoint {
+            path: path.display().to_string(),
+            source,
+        })
+    }
+
+    /// Save the checkpoint, replacing the previous one only once fully written
+    pub fn save(&self, path: &Path) -> Result<(), ReadError> {
+        let tmp_path = path.with_extension("tmp");
+
+        let file = File::create(&tmp_path).map_err(|err| ReadError::io(tmp_path.display(), err))?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self).map_err(|source| ReadError::Checkpoint {
+            path: tmp_path.display().to_string(),
+            source,
+        })?;
+        writer
+            .flush()
+            .map_err(|err| ReadError::io(tmp_path.display(), err))?;
+
+        fs::rename(&tmp_path, path).map_err(|err| ReadError::io(path.display(), err))
+    }
+}
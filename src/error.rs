@@ -0,0 +1,168 @@
+use std::fmt::Display;
+
+use bitcoin::consensus::encode;
+use bitcoin::p2p::Magic;
+use bitcoin::BlockHash;
+
+use crate::xor::XOR_KEY_SIZE;
+
+/// Error returned while reading blk files or decoding blocks
+#[derive(Debug)]
+pub enum ReadError {
+    /// I/O error on a file or directory
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The record does not start with the network magic
+    BadMagic {
+        file: String,
+        offset: u64,
+        found: Magic,
+    },
+    /// The record declares a size that can't hold a block
+    BadSize {
+        file: String,
+        offset: u64,
+        size: u32,
+    },
+    /// The offset to read from is past the end of the file
+    BadOffset {
+        file: String,
+        offset: u64,
+        size: u64,
+    },
+    /// The file ends before the end of the record
+    Truncated {
+        file: String,
+        offset: u64,
+        expected: u64,
+        available: u64,
+    },
+    /// The block header or transactions can't be decoded
+    Decode {
+        file: String,
+        offset: u64,
+        source: encode::Error,
+    },
+    /// The first block of the chain is not the genesis block of the network
+    BadGenesis {
+        file: String,
+        offset: u64,
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    /// The start block is not part of the best chain of the blk files
+    StartNotFound { hash: BlockHash },
+    /// The block body was not read (headers only mode)
+    NoBody { file: String, offset: u64 },
+    /// The file name does not follow the blkNNNNN.dat pattern
+    BadFileName { file: String },
+    /// The obfuscation key file does not contain a valid key
+    BadXorKey { file: String, size: usize },
+    /// The checkpoint can't be saved or loaded
+    Checkpoint {
+        path: String,
+        source: bincode::Error,
+    },
+    /// The orphans spill file can't be written or read back
+    Spill {
+        path: String,
+        source: bincode::Error,
+    },
+}
+
+impl ReadError {
+    pub(crate) fn io(path: impl Display, source: std::io::Error) -> ReadError {
+        ReadError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReadError::Io { path, source } => write!(f, "I/O error on {}: {}", path, source),
+            ReadError::BadMagic {
+                file,
+                offset,
+                found,
+            } => write!(
+                f,
+                "Magic is not correct in {} offset={}; got {}",
+                file, offset, found
+            ),
+            ReadError::BadSize { file, offset, size } => write!(
+                f,
+                "Invalid block size in {} offset={}; got {}",
+                file, offset, size
+            ),
+            ReadError::BadOffset { file, offset, size } => write!(
+                f,
+                "Offset {} is past the end of {} ({} bytes)",
+                offset, file, size
+            ),
+            ReadError::Truncated {
+                file,
+                offset,
+                expected,
+                available,
+            } => write!(
+                f,
+                "Truncated block in {} offset={}; expected {} bytes, got {}",
+                file, offset, expected, available
+            ),
+            ReadError::Decode {
+                file,
+                offset,
+                source,
+            } => write!(
+                f,
+                "Unable to decode block in {} offset={}: {}",
+                file, offset, source
+            ),
+            ReadError::BadGenesis {
+                file,
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Unexpected genesis block in {} offset={}; expected {}, got {}",
+                file, offset, expected, found
+            ),
+            ReadError::StartNotFound { hash } => {
+                write!(f, "Start block {} is not in the best chain", hash)
+            }
+            ReadError::NoBody { file, offset } => {
+                write!(f, "Block body was not read in {} offset={}", file, offset)
+            }
+            ReadError::BadFileName { file } => write!(f, "Invalid blk file name {}", file),
+            ReadError::BadXorKey { file, size } => write!(
+                f,
+                "Invalid obfuscation key in {}: expected {} bytes, got {}",
+                file, XOR_KEY_SIZE, size
+            ),
+            ReadError::Checkpoint { path, source } => {
+                write!(f, "Invalid checkpoint {}: {}", path, source)
+            }
+            ReadError::Spill { path, source } => {
+                write!(f, "Invalid orphans spill file {}: {}", path, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io { source, .. } => Some(source),
+            ReadError::Decode { source, .. } => Some(source),
+            ReadError::Checkpoint { source, .. } => Some(source),
+            ReadError::Spill { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
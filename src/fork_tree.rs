@@ -0,0 +1,80 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::ReadError;
+
+/// Block of a `ForkTree`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TreeBlock {
+    pub id: String,
+    pub parent: String,
+    /// Height relative to the head of the chain, None for orphans
+    pub depth: Option<u32>,
+    /// True if the block is part of the best chain
+    pub best: bool,
+}
+
+/// Snapshot of the blocks of a `Chain`, to inspect its forks and orphans
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ForkTree {
+    pub head: Option<String>,
+    pub best_tip: Option<String>,
+    /// Blocks attached to the chain, parents first
+    pub blocks: Vec<TreeBlock>,
+    /// Blocks waiting for their parent
+    pub orphans: Vec<TreeBlock>,
+}
+
+impl ForkTree {
+    /// Render the tree as a Graphviz DOT graph
+    /// The best chain is drawn in bold, orphans are dashed
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph chain {\n    rankdir=LR;\n");
+
+        for block in self.blocks.iter() {
+            let style = if block.best { "bold" } else { "solid" };
+            let depth = block.depth.unwrap_or_default();
+            writeln!(
+                dot,
+                "    \"{}\" [label=\"{}\\n{}\", style={}];",
+                block.id, block.id, depth, style
+            )
+            .unwrap();
+            if Some(&block.id) != self.head.as_ref() {
+                writeln!(dot, "    \"{}\" -> \"{}\";", block.parent, block.id).unwrap();
+            }
+        }
+
+        for block in self.orphans.iter() {
+            writeln!(dot, "    \"{}\" [style=dashed];", block.id).unwrap();
+            writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [style=dashed];",
+                block.parent, block.id
+            )
+            .unwrap();
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the tree as JSON
+    pub fn to_json(&self) -> String {
+        // The tree only holds strings, numbers and booleans
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write the tree to a file, as DOT if its extension is `dot` and JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<(), ReadError> {
+        let data = match path.extension() {
+            Some(extension) if extension == "dot" => self.to_dot(),
+            _ => self.to_json(),
+        };
+
+        fs::write(path, data).map_err(|err| ReadError::io(path.display(), err))
+    }
+}
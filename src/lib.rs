@@ -1,6 +1,31 @@
-mod chain;
 mod block;
+mod chain;
+mod checkpoint;
+mod error;
+mod fork_tree;
+mod progress;
+mod spill;
+mod xor;
 
-pub use block::LazyBlock;
+pub use block::signet_magic;
+pub use block::BlockEvent;
 pub use block::BlockReader;
 pub use block::BlockReaderOptions;
+pub use block::Blocks;
+pub use block::DuplicateBlock;
+pub use block::ForkReport;
+pub use block::LazyBlock;
+pub use block::SkipReason;
+pub use block::SkippedRange;
+pub use block::StaleBlock;
+pub use chain::Chain;
+pub use chain::ForkChoice;
+pub use chain::GetBlockIds;
+pub use chain::OrphanEviction;
+pub use checkpoint::BlockLocation;
+pub use checkpoint::Checkpoint;
+pub use error::ReadError;
+pub use fork_tree::ForkTree;
+pub use fork_tree::TreeBlock;
+pub use progress::Progress;
+pub use xor::XorKey;
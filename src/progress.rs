@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::checkpoint::BlockLocation;
+
+/// Progress of a read, see `BlockReader::set_progress_cb`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Bytes of the blk files before the current position
+    pub bytes_read: u64,
+    /// Bytes of all the blk files of the directory, without their preallocated space
+    pub total_bytes: u64,
+    /// Blocks read since the directory was opened, orphans and duplicates included
+    pub blocks_read: u64,
+    /// Height of the reader, see `BlockReader::height`
+    pub height: u32,
+    pub orphans: usize,
+    pub orphans_mem: usize,
+    /// Memory held by the blocks of the index, the blocks waiting to be delivered
+    /// and the hashes kept to recognise duplicates
+    pub mem: usize,
+    /// Time since the directory was opened
+    pub elapsed: Duration,
+    pub blocks_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Estimated time to read the remaining bytes, None until some bytes are read
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes_per_sec / 1_000_000.0
+    }
+
+    /// Return the fraction of the bytes read, between 0 and 1
+    pub fn ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+
+        (self.bytes_read as f64 / self.total_bytes as f64).min(1.0)
+    }
+}
+
+/// State of the block reader needed to compute its progress
+pub struct ProgressState {
+    pub position: Option<BlockLocation>,
+    pub height: u32,
+    pub orphans: usize,
+    pub orphans_mem: usize,
+    pub mem: usize,
+}
+
+/// Measure the throughput of a read since the directory was opened
+pub struct ProgressMeter {
+    started: Instant,
+    last_report: Instant,
+    // Bytes before the position the read started from
+    start_bytes: u64,
+    blocks_read: u64,
+    // Size of the blk files by index
+    file_sizes: BTreeMap<u32, u64>,
+    // Size of the preallocated space found at the end of the blk files by index
+    preallocated: BTreeMap<u32, u64>,
+}
+
+impl ProgressMeter {
+    pub fn new() -> ProgressMeter {
+        let now = Instant::now();
+        ProgressMeter {
+            started: now,
+            last_report: now,
+            start_bytes: 0,
+            blocks_read: 0,
+            file_sizes: BTreeMap::new(),
+            preallocated: BTreeMap::new(),
+        }
+    }
+
+    /// Start measuring from the given position
+    pub fn start(&mut self, position: Option<BlockLocation>) {
+        self.started = Instant::now();
+        self.last_report = self.started;
+        self.start_bytes = self.bytes_read(position);
+        self.blocks_read = 0;
+    }
+
+    /// Set the size of the blk files, read and unread
+    pub fn set_file_sizes(&mut self, file_sizes: BTreeMap<u32, u64>) {
+        self.file_sizes = file_sizes;
+    }
+
+    /// Set the size of the preallocated space at the end of a blk file
+    pub fn set_preallocated(&mut self, blk_index: u32, len: u64) {
+        self.preallocated.insert(blk_index, len);
+    }
+
+    pub fn block_read(&mut self) {
+        self.blocks_read += 1;
+    }
+
+    /// Return true if the last report is older than the interval
+    pub fn due(&self, interval: Duration) -> bool {
+        self.last_report.elapsed() >= interval
+    }
+
+    /// Return the progress and reset the interval
+    pub fn report(&mut self, state: ProgressState) -> Progress {
+        self.last_report = Instant::now();
+        self.progress(state)
+    }
+
+    pub fn progress(&self, state: ProgressState) -> Progress {
+        let elapsed = self.started.elapsed();
+        let bytes_read = self.bytes_read(state.position);
+        let total_bytes = self.data_size(..).max(bytes_read);
+
+        let secs = elapsed.as_secs_f64();
+        let (blocks_per_sec, bytes_per_sec) = if secs > 0.0 {
+            (
+                self.blocks_read as f64 / secs,
+                bytes_read.saturating_sub(self.start_bytes) as f64 / secs,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        let eta = if bytes_per_sec > 0.0 {
+            let secs_left = (total_bytes - bytes_read) as f64 / bytes_per_sec;
+            Some(Duration::from_secs_f64(secs_left))
+        } else {
+            None
+        };
+
+        Progress {
+            bytes_read,
+            total_bytes,
+            blocks_read: self.blocks_read,
+            height: state.height,
+            orphans: state.orphans,
+            orphans_mem: state.orphans_mem,
+            mem: state.mem,
+            elapsed,
+            blocks_per_sec,
+            bytes_per_sec,
+            eta,
+        }
+    }
+
+    // Bytes of the files before the position, the file of the position included up to it
+    fn bytes_read(&self, position: Option<BlockLocation>) -> u64 {
+        let position = match position {
+            Some(position) => position,
+            None => return 0,
+        };
+
+        self.data_size(..position.blk_index) + position.offset
+    }
+
+    // Bytes of the files in the range of indexes, without their preallocated space
+    fn data_size(&self, range: impl RangeBounds<u32>) -> u64 {
+        self.file_sizes
+            .range(range)
+            .map(|(index, size)| {
+                let preallocated = self.preallocated.get(index).copied().unwrap_or(0);
+                size.saturating_sub(preallocated)
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(blk_index: u32, offset: u64) -> ProgressState {
+        ProgressState {
+            position: Some(BlockLocation { blk_index, offset }),
+            height: 0,
+            orphans: 0,
+            orphans_mem: 0,
+            mem: 0,
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut meter = ProgressMeter::new();
+        meter.set_file_sizes(BTreeMap::from([(0, 1000), (1, 1000), (2, 500)]));
+        meter.start(Some(BlockLocation {
+            blk_index: 0,
+            offset: 500,
+        }));
+
+        let progress = meter.progress(state(1, 250));
+        assert_eq!(progress.bytes_read, 1250);
+        assert_eq!(progress.total_bytes, 2500);
+        assert_eq!(progress.ratio(), 0.5);
+
+        // The preallocated space of the last file will not be read
+        meter.set_preallocated(2, 250);
+        let progress = meter.progress(state(2, 250));
+        assert_eq!(progress.bytes_read, 2250);
+        assert_eq!(progress.total_bytes, 2250);
+        assert_eq!(progress.ratio(), 1.0);
+
+        // The rate only counts the bytes read since the start
+        std::thread::sleep(Duration::from_millis(10));
+        meter.block_read();
+        let progress = meter.report(state(1, 250));
+        assert_eq!(progress.blocks_read, 1);
+        let expected = 750.0 / progress.elapsed.as_secs_f64();
+        assert!((progress.bytes_per_sec - expected).abs() / expected < 0.1);
+        assert!(progress.eta.is_some());
+        assert!(!meter.due(Duration::from_secs(60)));
+        assert!(meter.due(Duration::ZERO));
+    }
+}
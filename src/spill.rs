@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+use bitcoin::BlockHash;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::checkpoint::BlockLocation;
+use crate::error::ReadError;
+
+/// Number of spill files created by this process, used to name them
+static SPILL_FILES: AtomicU32 = AtomicU32::new(0);
+
+/// Orphan block written to the spill file
+struct Spilled {
+    pos: u64,
+    len: usize,
+    location: BlockLocation,
+}
+
+/// Temporary file holding the orphan blocks evicted from memory
+/// Blocks are reloaded once their parent is attached to the chain,
+/// the file is removed when dropped
+pub struct OrphanSpill {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    // Bytes of the blocks not read back yet
+    live: u64,
+    // Spilled blocks by parent hash
+    blocks: BTreeMap<BlockHash, Vec<Spilled>>,
+}
+
+impl OrphanSpill {
+    /// Create a new spill file in the given directory
+    pub fn create(dir_path: &Path) -> Result<OrphanSpill, ReadError> {
+        let path = dir_path.join(format!(
+            "blk-reader-orphans-{}-{}.tmp",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|err| ReadError::io(path.display(), err))?;
+
+        Ok(OrphanSpill {
+            path,
+            file,
+            len: 0,
+            live: 0,
+            blocks: BTreeMap::new(),
+        })
+    }
+
+    /// Write a block waiting for the given parent
+    pub fn push<T: Serialize>(
+        &mut self,
+        parent: BlockHash,
+        location: BlockLocation,
+        block: &T,
+    ) -> Result<(), ReadError> {
+        let data = bincode::serialize(block).map_err(|source| self.error(source))?;
+        self.file
+            .seek(SeekFrom::Start(self.len))
+            .and_then(|_| self.file.write_all(&data))
+            .map_err(|err| ReadError::io(self.path.display(), err))?;
+
+        self.blocks.entry(parent).or_default().push(Spilled {
+            pos: self.len,
+            len: data.len(),
+            location,
+        });
+        self.len += data.len() as u64;
+        self.live += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Read back the blocks waiting for the given parent and forget them
+    pub fn take<T: DeserializeOwned>(&mut self, parent: &BlockHash) -> Result<Vec<T>, ReadError> {
+        let spilled = match self.blocks.remove(parent) {
+            Some(spilled) => spilled,
+            None => return Ok(vec![]),
+        };
+
+        let mut blocks = vec![];
+        for block in spilled {
+            let mut data = vec![0; block.len];
+            self.file
+                .seek(SeekFrom::Start(block.pos))
+                .and_then(|_| self.file.read_exact(&mut data))
+                .map_err(|err| ReadError::io(self.path.display(), err))?;
+            blocks.push(bincode::deserialize(&data).map_err(|source| self.error(source))?);
+            self.live -= block.len as u64;
+        }
+
+        // Reclaim the space of the blocks read back once it is most of the file
+        if self.len > 2 * self.live {
+            self.compact()?;
+        }
+
+        Ok(blocks)
+    }
+
+    /// Move the blocks not read back yet to the beginning of the file and truncate it
+    fn compact(&mut self) -> Result<(), ReadError> {
+        let mut spilled = self.blocks.values_mut().flatten().collect::<Vec<_>>();
+        spilled.sort_by_key(|block| block.pos);
+
+        let mut len = 0;
+        for block in spilled {
+            if block.pos != len {
+                let mut data = vec![0; block.len];
+                self.file
+                    .seek(SeekFrom::Start(block.pos))
+                    .and_then(|_| self.file.read_exact(&mut data))
+                    .and_then(|_| self.file.seek(SeekFrom::Start(len)))
+                    .and_then(|_| self.file.write_all(&data))
+                    .map_err(|err| ReadError::io(self.path.display(), err))?;
+                block.pos = len;
+            }
+            len += block.len as u64;
+        }
+
+        self.file
+            .set_len(len)
+            .map_err(|err| ReadError::io(self.path.display(), err))?;
+        self.len = len;
+
+        Ok(())
+    }
+
+    /// Return the number of blocks in the spill file
+    pub fn len(&self) -> usize {
+        self.blocks.values().map(Vec::len).sum()
+    }
+
+    /// Return the position of the spilled blocks in the blk files
+    pub fn locations(&self) -> impl Iterator<Item = BlockLocation> + '_ {
+        self.blocks
+            .values()
+            .flat_map(|blocks| blocks.iter().map(|block| block.location))
+    }
+
+    fn error(&self, source: bincode::Error) -> ReadError {
+        ReadError::Spill {
+            path: self.path.display().to_string(),
+            source,
+        }
+    }
+}
+
+impl Drop for OrphanSpill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_spill() {
+        let dir = std::env::temp_dir();
+        let mut spill = OrphanSpill::create(&dir).unwrap();
+        let path = spill.path.clone();
+
+        let parent = BlockHash::all_zeros();
+        let location = BlockLocation {
+            blk_index: 1,
+            offset: 8,
+        };
+        spill.push(parent, location, &"first").unwrap();
+        spill.push(parent, location, &"second").unwrap();
+        assert_eq!(spill.len(), 2);
+        assert_eq!(spill.locations().collect::<Vec<_>>(), vec![location; 2]);
+
+        // The blocks left are moved to the beginning of the file
+        let other = BlockHash::from_byte_array([1; 32]);
+        spill.push(other, location, &"third").unwrap();
+        let blocks: Vec<String> = spill.take(&parent).unwrap();
+        assert_eq!(blocks, vec!["first", "second"]);
+        assert_eq!(spill.len(), 1);
+        assert!(spill.take::<String>(&parent).unwrap().is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), spill.len);
+        assert_eq!(spill.len, spill.live);
+
+        // The file is emptied once every block is read back
+        let blocks: Vec<String> = spill.take(&other).unwrap();
+        assert_eq!(blocks, vec!["third"]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        drop(spill);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::io::Error;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use crate::error::ReadError;
+
+/// Size of the obfuscation key used by Bitcoin Core (since v28)
+pub const XOR_KEY_SIZE: usize = 8;
+
+/// Name of the file holding the obfuscation key in the blocks directory
+const XOR_KEY_FILE: &str = "xor.dat";
+
+pub type XorKey = [u8; XOR_KEY_SIZE];
+
+/// Read the obfuscation key stored next to the blk files
+/// Return None if the directory has no xor.dat file (Bitcoin Core < 28)
+pub fn read_xor_key(dir_path: &Path) -> Result<Option<XorKey>, ReadError> {
+    let key_path = dir_path.join(XOR_KEY_FILE);
+    if !key_path.is_file() {
+        return Ok(None);
+    }
+
+    let data = fs::read(&key_path).map_err(|err| ReadError::io(key_path.display(), err))?;
+    let key: XorKey = data
+        .as_slice()
+        .try_into()
+        .map_err(|_| ReadError::BadXorKey {
+            file: key_path.display().to_string(),
+            size: data.len(),
+        })?;
+
+    Ok(Some(key))
+}
+
+/// Reader that de-obfuscates the bytes of a blk file on the fly
+/// The key is applied relatively to the position in the file, so `pos` must
+/// be the offset of the next byte returned by `inner`
+pub struct XorReader<R> {
+    inner: R,
+    key: XorKey,
+    pos: u64,
+}
+
+impl<R> XorReader<R> {
+    pub fn new(inner: R, key: XorKey, pos: u64) -> XorReader<R> {
+        XorReader { inner, key, pos }
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let read = self.inner.read(buf)?;
+        xor(&mut buf[..read], &self.key, self.pos);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Apply the obfuscation key to `data` located at `pos` in the file
+pub fn xor(data: &mut [u8], key: &XorKey, pos: u64) {
+    // A zero key (or no xor.dat) leaves the data untouched
+    if key.iter().all(|b| *b == 0) {
+        return;
+    }
+
+    let start = (pos % XOR_KEY_SIZE as u64) as usize;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[(start + i) % XOR_KEY_SIZE];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xor_reader() {
+        let key: XorKey = [1, 2, 3, 4, 5, 6, 7, 8];
+        let plain: Vec<u8> = (0..20).collect();
+
+        let mut obfuscated = plain.clone();
+        xor(&mut obfuscated, &key, 0);
+        assert_ne!(obfuscated, plain);
+
+        // Reading from an offset must use the matching part of the key
+        let mut reader = XorReader::new(&obfuscated[5..], key, 5);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, plain[5..]);
+    }
+}